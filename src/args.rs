@@ -0,0 +1,297 @@
+use num_bigint::{BigInt, Sign};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Expr, Sig};
+use crate::combine::Program;
+use crate::eval::{EvalError, EvalState, Type, Val};
+
+/// Something wrong with the command-line arguments given to `main`
+#[derive(Debug)]
+pub enum ArgError {
+    NoMain,
+    HiddenArgs,
+    WrongNumberOfArgs(usize, usize),
+    /// Parameter name, the text given, the expected type and what went wrong
+    Invalid(String, String, Type, String),
+    /// The parameter's type couldn't be evaluated
+    BadType(String, Box<EvalError>),
+}
+
+/// The signature arguments come from. If `main` has more than one, the first is used.
+fn main_sig(program: &Program) -> Result<&Sig, ArgError> {
+    let func = program.funcs.get("main").ok_or(ArgError::NoMain)?;
+    let sig = &func.sigs[0];
+    if !sig.hidden.is_empty() {
+        return Err(ArgError::HiddenArgs);
+    }
+    Ok(sig)
+}
+
+/// A `main` with a single `list string` parameter gets all the arguments as they are, so that
+/// it can do its own parsing.
+fn takes_raw_args(sig: &Sig) -> bool {
+    sig.args.len() == 1 && sig.args[0].1 == Expr::call("list", vec![Expr::var("string")])
+}
+
+/// Converts each command-line argument to a value of the type declared for the corresponding
+/// parameter of `main`. Types may depend on the values of earlier parameters, and get evaluated
+/// within the limits of the given state.
+pub fn decode_args(program: &Program, args: &[String], state: &mut EvalState) -> Result<Vec<Val>, ArgError> {
+    let sig = main_sig(program)?;
+    if takes_raw_args(sig) {
+        return Ok(vec![Val::Array(args.iter().map(|s| Val::String(s.clone())).collect())]);
+    }
+    if args.len() != sig.args.len() {
+        return Err(ArgError::WrongNumberOfArgs(sig.args.len(), args.len()));
+    }
+
+    let mut env = HashMap::new();
+    let mut result = vec![];
+    for ((name, typ), text) in sig.args.iter().zip(args) {
+        let t = program.eval_with_env(typ, state, &env)
+            .and_then(|t| t.unwrap_type())
+            .map_err(|e| ArgError::BadType(name.clone(), Box::new(e)))?;
+        // Strings only need quotes when they're inside an array
+        let value = if t == Type::String {
+            Val::String(text.clone())
+        } else {
+            let mut input = text.as_str();
+            decode(&mut input, &t).and_then(|v| {
+                if input.trim().is_empty() {
+                    Ok(v)
+                } else {
+                    Err(format!("unexpected {}", input.trim()))
+                }
+            }).map_err(|e| ArgError::Invalid(name.clone(), text.clone(), t, e))?
+        };
+        env.insert(name.clone(), value.clone());
+        result.push(value);
+    }
+    Ok(result)
+}
+
+/// Lists the parameters of `main`, for `--help`
+pub fn usage(program: &Program, script: &str) -> Result<String, ArgError> {
+    let sig = main_sig(program)?;
+    if takes_raw_args(sig) {
+        return Ok(format!("Usage: {} [ARGS]...", script));
+    }
+    let mut result = format!("Usage: {}", script);
+    for (name, _) in &sig.args {
+        result += &format!(" <{}>", name);
+    }
+    if !sig.args.is_empty() {
+        result += "\n\nArguments:";
+        for (name, typ) in &sig.args {
+            result += &format!("\n    {} : {}", name, typ);
+        }
+    }
+    Ok(result)
+}
+
+/// Reads a value of the given type from the start of the input, using a JSON-like syntax. Array
+/// elements may be separated by commas or just by whitespace.
+fn decode(input: &mut &str, t: &Type) -> Result<Val, String> {
+    *input = input.trim_start();
+    match t {
+        Type::Int | Type::Uint => {
+            let len = input.find(|c: char| !(c.is_ascii_digit() || c == '-')).unwrap_or(input.len());
+            let n: BigInt = input[..len].parse().map_err(|_| "expected a number".to_owned())?;
+            if *t == Type::Uint && n.sign() == Sign::Minus {
+                return Err("expected a number that isn't negative".to_owned());
+            }
+            *input = &input[len..];
+            Ok(Val::Int(n))
+        }
+        Type::Bool => {
+            let len = input.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(input.len());
+            let b = match &input[..len] {
+                "true" => Type::True,
+                "false" => Type::False,
+                _ => return Err("expected true or false".to_owned()),
+            };
+            *input = &input[len..];
+            Ok(Val::Type(b))
+        }
+        // The only value of `true` is `[]`
+        Type::True => Ok(Val::Array(decode_array(input, |_| None)?)),
+        Type::False => Err("false has no values".to_owned()),
+        Type::String => decode_string(input),
+        Type::List(elem) => Ok(Val::Array(decode_array(input, |_| Some(elem))?)),
+        Type::Vector(elem, n) => {
+            let xs = decode_array(input, |_| Some(elem))?;
+            if xs.len() != *n {
+                return Err(format!("expected {} elements but got {}", n, xs.len()));
+            }
+            Ok(Val::Array(xs))
+        }
+        Type::Tuple(ts) => {
+            let xs = decode_array(input, |i| ts.get(i))?;
+            if xs.len() != ts.len() {
+                return Err(format!("expected {} elements but got {}", ts.len(), xs.len()));
+            }
+            Ok(Val::Array(xs))
+        }
+        Type::Eq | Type::Type | Type::Unknown => Err("types can't be given on the command line".to_owned()),
+        Type::Pi(_) => Err("functions can't be given on the command line".to_owned()),
+        Type::Record(_) | Type::Sum(_) => Err("records and tagged values can't be given on the command line".to_owned()),
+    }
+}
+
+/// The element types are looked up by index. The array is too long if there isn't one.
+fn decode_array<'a>(input: &mut &str, elem: impl Fn(usize) -> Option<&'a Type>) -> Result<Vec<Val>, String> {
+    *input = input.strip_prefix('[').ok_or("expected [")?;
+    let mut result = vec![];
+    loop {
+        *input = input.trim_start();
+        if let Some(rest) = input.strip_prefix(']') {
+            *input = rest;
+            return Ok(result);
+        }
+        let t = elem(result.len()).ok_or("too many elements")?;
+        result.push(decode(input, t)?);
+        *input = input.trim_start();
+        if let Some(rest) = input.strip_prefix(',') {
+            *input = rest;
+        }
+    }
+}
+
+/// Accepts the same escapes as string literals in a script
+fn decode_string(input: &mut &str) -> Result<Val, String> {
+    let mut chars = input.strip_prefix('"').ok_or("expected a quoted string")?.chars();
+    let mut result = String::new();
+    loop {
+        match chars.next() {
+            None => return Err("unterminated string".to_owned()),
+            Some('"') => break,
+            Some('\\') => result.push(match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('u') => {
+                    let rest = chars.as_str();
+                    let code = rest.strip_prefix('{')
+                        .and_then(|r| r.find('}').map(|end| &r[..end]))
+                        .ok_or("expected \\u{...}")?;
+                    let c = u32::from_str_radix(code, 16).ok()
+                        .and_then(std::char::from_u32)
+                        .ok_or("invalid unicode escape")?;
+                    chars = rest[code.len() + 2..].chars();
+                    c
+                }
+                _ => return Err("unknown escape".to_owned()),
+            }),
+            Some(c) => result.push(c),
+        }
+    }
+    *input = chars.as_str();
+    Ok(Val::String(result))
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::NoMain => write!(f, "the script has no main function"),
+            ArgError::HiddenArgs => write!(f, "main can't have hidden arguments"),
+            ArgError::WrongNumberOfArgs(expected, got) => write!(f, "main takes {} arguments but was given {}", expected, got),
+            ArgError::Invalid(name, text, t, message) => write!(f, "argument {} should be {}, but got {}: {}", name, t, text, message),
+            ArgError::BadType(name, e) => write!(f, "cannot work out the type of argument {}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine, parse};
+
+    fn program(script: &str) -> Program {
+        let prelude = include_str!("prelude.jd");
+        combine::combine(&parse::parse(prelude, 0).unwrap(), &parse::parse(script, prelude.len() + 1).unwrap()).unwrap()
+    }
+
+    /// Decodes a single argument for a `main` whose parameter has the given type
+    fn decode_one(typ: &str, text: &str) -> Result<Val, ArgError> {
+        let program = program(&format!("main (x:{}) : int = 0;", typ));
+        decode_args(&program, &[text.to_owned()], &mut EvalState::new(None)).map(|mut vs| vs.remove(0))
+    }
+
+    fn int(n: i32) -> Val {
+        Val::Int(n.into())
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(decode_one("int", "-12").unwrap(), int(-12));
+        assert_eq!(decode_one("uint", "12").unwrap(), int(12));
+        assert!(decode_one("uint", "-12").is_err());
+        assert!(decode_one("int", "twelve").is_err());
+        assert!(decode_one("int", "12 13").is_err());
+    }
+
+    #[test]
+    fn bools_and_units() {
+        assert_eq!(decode_one("bool", "true").unwrap(), Val::Type(Type::True));
+        assert_eq!(decode_one("bool", "false").unwrap(), Val::Type(Type::False));
+        assert!(decode_one("bool", "yes").is_err());
+        assert_eq!(decode_one("true", "[]").unwrap(), Val::Array(vec![]));
+        assert!(decode_one("true", "true").is_err());
+        assert!(decode_one("true", "[1]").is_err());
+        assert!(decode_one("false", "false").is_err());
+        assert!(decode_one("false", "[]").is_err());
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(decode_one("string", "a \"b\"").unwrap(), Val::String("a \"b\"".to_owned()));
+        let escaped = r#"["a\"\\\n\r\t\0\u{e9}"]"#;
+        assert_eq!(decode_one("vector string 1", escaped).unwrap(), Val::Array(vec![Val::String("a\"\\\n\r\t\0é".to_owned())]));
+        assert!(decode_one("vector string 1", r#"["\q"]"#).is_err());
+        assert!(decode_one("vector string 1", r#"["\u{110000}"]"#).is_err());
+        assert!(decode_one("vector string 1", r#"["abc]"#).is_err());
+    }
+
+    #[test]
+    fn arrays() {
+        assert_eq!(decode_one("list int", "[1, 2 3]").unwrap(), Val::Array(vec![int(1), int(2), int(3)]));
+        assert_eq!(decode_one("vector uint 2", "[1 2]").unwrap(), Val::Array(vec![int(1), int(2)]));
+        assert!(decode_one("vector uint 2", "[1]").is_err());
+        assert_eq!(decode_one("tuple [int string]", r#"[1 "a"]"#).unwrap(), Val::Array(vec![int(1), Val::String("a".to_owned())]));
+        assert!(decode_one("tuple [int string]", "[1 2]").is_err());
+        assert!(decode_one("tuple [int]", "[1 2]").is_err());
+    }
+
+    #[test]
+    fn types_that_cant_be_given() {
+        for typ in ["eq", "type", "int -> int", "{x:int}", "{a:int | b}"] {
+            assert!(matches!(decode_one(typ, "1"), Err(ArgError::Invalid(..))), "{}", typ);
+        }
+    }
+
+    #[test]
+    fn types_depend_on_earlier_arguments() {
+        let program = program("main (n:uint) (xs:vector int n) : int = 0;");
+        let args = |xs: &[&str]| decode_args(&program, &xs.iter().map(|s| s.to_string()).collect::<Vec<_>>(), &mut EvalState::new(None));
+        assert_eq!(args(&["2", "[5 6]"]).unwrap(), vec![int(2), Val::Array(vec![int(5), int(6)])]);
+        assert!(args(&["3", "[5 6]"]).is_err());
+        assert!(matches!(args(&["3"]), Err(ArgError::WrongNumberOfArgs(2, 1))));
+    }
+
+    #[test]
+    fn types_are_evaluated_within_the_limits() {
+        let program = program("forever (n:uint) : uint = forever n;\nmain (xs:vector int (forever 1)) : int = 0;");
+        let result = decode_args(&program, &["[]".to_owned()], &mut EvalState::new(Some(100)));
+        assert!(matches!(result, Err(ArgError::BadType(_, e)) if e.to_string().starts_with("ran out of fuel")));
+    }
+
+    #[test]
+    fn raw_args() {
+        let program = program("main (args:list string) : int = 0;");
+        assert_eq!(decode_args(&program, &["1".to_owned(), "[".to_owned()], &mut EvalState::new(None)).unwrap(), vec![Val::Array(vec![Val::String("1".to_owned()), Val::String("[".to_owned())])]);
+    }
+}
@@ -1,21 +1,584 @@
-use num_bigint::BigInt;
+use num_bigint::{BigInt, Sign};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::parse::{Infix, INFIX_OPS};
 
 #[derive(Clone, Debug)]
 pub struct Script {
     pub decls: Vec<(String, Decl)>,
 }
 
+/// A single line of a declaration. Only one line for each name may have a body; the others
+/// just give extra type signatures.
 #[derive(Clone, Debug)]
 pub struct Decl {
+    pub sig: Sig,
+    pub body: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sig {
+    /// Written in curly brackets. These may only be used in types, never in the body.
+    pub hidden: Vec<(String, Expr)>,
     pub args: Vec<(String, Expr)>,
     pub ret: Expr,
-    pub body: Expr,
+}
+
+/// A range of bytes in the source map. See `source::Sources`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Spans don't take part in comparisons, so that expressions compare structurally.
+/// Expressions made up by the type checker don't have a span.
+#[derive(Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Option<Span>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum Expr {
+pub enum ExprKind {
     Int(BigInt),
+    Str(String),
     Var(String),
-    Call(String, Vec<Expr>),
+    /// Function name, named hidden arguments and normal arguments
+    Call(String, Vec<(String, Expr)>, Vec<Expr>),
+    /// A function value given by any other expression and its arguments, written `(f x) y`
+    Apply(Box<Expr>, Vec<Expr>),
     Array(Vec<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Name, optional type annotation, value and the body it's visible in
+    Let(String, Option<Box<Expr>>, Box<Expr>, Box<Expr>),
+    /// Parameter name, its type and the body, written `\(x:t) -> body`
+    Lambda(String, Box<Expr>, Box<Expr>),
+    /// The type of functions taking a parameter of the first type and returning the second, which
+    /// may mention the parameter. Written `pi (x:a) -> b`, or `a -> b` when the parameter is `_`.
+    Pi(String, Box<Expr>, Box<Expr>),
+    /// The type of records with these fields, written `{x:t, y:u}`. Each field's type may mention
+    /// the fields before it.
+    RecordType(Vec<(String, Expr)>),
+    /// The type of values made from one of these tags and a value of its type, written
+    /// `{a:t | b:u}`
+    SumType(Vec<(String, Expr)>),
+    /// A record with these field values, written `{x = 1, y = 2}`
+    Record(Vec<(String, Expr)>),
+    /// A tag and its value, written `#a x`, or just `#a` when the value is `[]`
+    Tag(String, Box<Expr>),
+    /// A field of a record, written `r.x`
+    Field(Box<Expr>, String),
+    /// The value being matched, then for each tag the name its value gets and the result, written
+    /// `match x {#a y -> y, #b z -> 0}`
+    Match(Box<Expr>, Vec<(String, String, Expr)>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Span {
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr {
+            kind,
+            span: Some(span),
+        }
+    }
+
+    pub fn var(name: &str) -> Self {
+        ExprKind::Var(name.to_owned()).into()
+    }
+
+    pub fn call(f: &str, args: Vec<Expr>) -> Self {
+        ExprKind::Call(f.to_owned(), vec![], args).into()
+    }
+
+    /// Replaces free variables with the given expressions. Inside a `let`, lambda, function type
+    /// or match arm, the variable it binds hides any replacement for the same name, and gets
+    /// renamed if a replacement mentions it, so that the replacement still means what it did
+    /// outside. The fields of a record type can't be renamed, so they still hide anything with
+    /// the same name that a replacement mentions.
+    pub fn substitute(&self, vars: &HashMap<String, Expr>) -> Expr {
+        let sub = |x: &Expr| Box::new(x.substitute(vars));
+        let sub_bound = |x: &str, body: &Expr| {
+            let (x, inner) = bind(x, body, vars);
+            (x, Box::new(body.substitute(&inner)))
+        };
+        let kind = match &self.kind {
+            ExprKind::Int(_) | ExprKind::Str(_) => return self.clone(),
+            ExprKind::Var(x) => return vars.get(x).cloned().unwrap_or_else(|| self.clone()),
+            ExprKind::Call(f, hs, xs) => {
+                let hs: Vec<_> = hs.iter().map(|(h, x)| (h.clone(), x.substitute(vars))).collect();
+                let xs: Vec<_> = xs.iter().map(|x| x.substitute(vars)).collect();
+                match vars.get(f) {
+                    // Calls can only name the function, so the replacement gets bound to a name,
+                    // which mustn't be one the arguments use
+                    Some(g) => {
+                        let f = fresh_name(f, |y| hs.iter().map(|h| &h.1).chain(&xs).any(|x| x.mentions(y)));
+                        let call = ExprKind::Call(f.clone(), hs, xs);
+                        ExprKind::Let(f, None, Box::new(g.clone()), Box::new(Expr { kind: call, span: self.span }))
+                    }
+                    None => ExprKind::Call(f.clone(), hs, xs),
+                }
+            }
+            ExprKind::Apply(g, xs) => ExprKind::Apply(sub(g), xs.iter().map(|x| x.substitute(vars)).collect()),
+            ExprKind::Array(xs) => ExprKind::Array(xs.iter().map(|x| x.substitute(vars)).collect()),
+            ExprKind::If(c, a, b) => ExprKind::If(sub(c), sub(a), sub(b)),
+            ExprKind::Let(x, t, v, b) => {
+                let (x, b) = sub_bound(x, b);
+                ExprKind::Let(x, t.as_deref().map(sub), sub(v), b)
+            }
+            ExprKind::Lambda(x, t, b) => {
+                let (x, b) = sub_bound(x, b);
+                ExprKind::Lambda(x, sub(t), b)
+            }
+            ExprKind::Pi(x, a, b) => {
+                let (x, b) = sub_bound(x, b);
+                ExprKind::Pi(x, sub(a), b)
+            }
+            ExprKind::RecordType(fs) => {
+                let mut inner = vars.clone();
+                let mut fields = vec![];
+                for (x, t) in fs {
+                    fields.push((x.clone(), t.substitute(&inner)));
+                    inner.remove(x);
+                }
+                ExprKind::RecordType(fields)
+            }
+            ExprKind::SumType(fs) => ExprKind::SumType(fs.iter().map(|(x, t)| (x.clone(), t.substitute(vars))).collect()),
+            ExprKind::Record(fs) => ExprKind::Record(fs.iter().map(|(x, v)| (x.clone(), v.substitute(vars))).collect()),
+            ExprKind::Tag(a, x) => ExprKind::Tag(a.clone(), sub(x)),
+            ExprKind::Field(r, x) => ExprKind::Field(sub(r), x.clone()),
+            ExprKind::Match(x, arms) => ExprKind::Match(
+                sub(x),
+                arms.iter().map(|(a, y, b)| {
+                    let (y, b) = sub_bound(y, b);
+                    (a.clone(), y, *b)
+                }).collect(),
+            ),
+            ExprKind::And(a, b) => ExprKind::And(sub(a), sub(b)),
+            ExprKind::Or(a, b) => ExprKind::Or(sub(a), sub(b)),
+        };
+        Expr { kind, span: self.span }
+    }
+}
+
+/// The name to give a variable bound around `body`, and the replacements to make inside it. The
+/// variable keeps its name unless a replacement that gets used mentions it.
+fn bind(x: &str, body: &Expr, vars: &HashMap<String, Expr>) -> (String, HashMap<String, Expr>) {
+    let mut inner = vars.clone();
+    inner.remove(x);
+    let used: Vec<_> = inner.iter().filter(|(y, _)| body.mentions(y)).map(|(y, v)| (y.clone(), v.clone())).collect();
+    if !used.iter().any(|(_, v)| v.mentions(x)) {
+        return (x.to_owned(), inner);
+    }
+    let fresh = fresh_name(x, |y| body.mentions(y) || used.iter().any(|(_, v)| v.mentions(y)));
+    inner.insert(x.to_owned(), Expr::var(&fresh));
+    (fresh, inner)
+}
+
+/// A name like `x` that isn't taken, adding primes until it isn't
+fn fresh_name(x: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut fresh = x.to_owned();
+    while taken(&fresh) {
+        fresh.push('\'');
+    }
+    fresh
+}
+
+impl Expr {
+    /// Whether the variable appears free, i.e. not hidden by a local of the same name
+    pub fn mentions(&self, x: &str) -> bool {
+        let bound = |y: &str, body: &Expr| y != x && body.mentions(x);
+        match &self.kind {
+            ExprKind::Int(_) | ExprKind::Str(_) => false,
+            ExprKind::Var(y) => y == x,
+            ExprKind::Call(f, hs, xs) => f == x || hs.iter().map(|h| &h.1).chain(xs).any(|e| e.mentions(x)),
+            ExprKind::Apply(g, xs) => g.mentions(x) || xs.iter().any(|e| e.mentions(x)),
+            ExprKind::Array(xs) => xs.iter().any(|e| e.mentions(x)),
+            ExprKind::If(c, a, b) => c.mentions(x) || a.mentions(x) || b.mentions(x),
+            ExprKind::Let(y, t, v, b) => t.as_ref().is_some_and(|t| t.mentions(x)) || v.mentions(x) || bound(y, b),
+            ExprKind::Lambda(y, a, b) | ExprKind::Pi(y, a, b) => a.mentions(x) || bound(y, b),
+            ExprKind::RecordType(fs) => {
+                for (y, t) in fs {
+                    if t.mentions(x) {
+                        return true;
+                    }
+                    if y == x {
+                        return false;
+                    }
+                }
+                false
+            }
+            ExprKind::SumType(fs) | ExprKind::Record(fs) => fs.iter().any(|f| f.1.mentions(x)),
+            ExprKind::Tag(_, e) | ExprKind::Field(e, _) => e.mentions(x),
+            ExprKind::Match(e, arms) => e.mentions(x) || arms.iter().any(|(_, y, b)| bound(y, b)),
+            ExprKind::And(a, b) | ExprKind::Or(a, b) => a.mentions(x) || b.mentions(x),
+        }
+    }
+
+    /// The `[]` that a tag written on its own carries
+    pub fn is_empty_array(&self) -> bool {
+        matches!(&self.kind, ExprKind::Array(xs) if xs.is_empty())
+    }
+}
+
+impl From<ExprKind> for Expr {
+    fn from(kind: ExprKind) -> Self {
+        Expr { kind, span: None }
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.kind == other.kind
+    }
+}
+
+impl Eq for Expr {}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+// Precedence levels for printing, from loosest to tightest. The levels in between belong to the
+// infix operators, which are numbered from 1 in the order they appear in `INFIX_OPS`.
+const PREC_IF: usize = 0;
+const PREC_PREFIX: usize = INFIX_OPS.len() + 1;
+const PREC_APPLICATION: usize = PREC_PREFIX + 1;
+const PREC_TIGHT: usize = PREC_APPLICATION + 1;
+
+impl Expr {
+    /// The operator, precedence level and operands, if this can be written as an infix expression
+    fn as_infix(&self) -> Option<(&'static str, usize, &Expr, &Expr)> {
+        let (a, b) = match &self.kind {
+            ExprKind::And(a, b) | ExprKind::Or(a, b) => (&**a, &**b),
+            ExprKind::Call(_, hs, xs) if hs.is_empty() && xs.len() == 2 => (&xs[0], &xs[1]),
+            _ => return None,
+        };
+        for (level, ops) in INFIX_OPS.iter().enumerate() {
+            for (op, infix) in ops.iter() {
+                let found = match (infix, &self.kind) {
+                    (Infix::And, ExprKind::And(..)) | (Infix::Or, ExprKind::Or(..)) => true,
+                    (Infix::Call(g), ExprKind::Call(f, _, _)) => f == g,
+                    _ => false,
+                };
+                if found {
+                    return Some((op, level + 1, a, b));
+                }
+            }
+        }
+        None
+    }
+
+    fn as_neg(&self) -> Option<&Expr> {
+        match &self.kind {
+            ExprKind::Call(f, hs, xs) if f == "neg" && hs.is_empty() && xs.len() == 1 => Some(&xs[0]),
+            _ => None,
+        }
+    }
+
+    /// Whether the expression is written starting with a record literal, as in `{x = 1}.x`
+    fn starts_with_record(&self) -> bool {
+        match &self.kind {
+            ExprKind::Record(_) => true,
+            ExprKind::Field(r, _) => r.starts_with_record(),
+            _ => false,
+        }
+    }
+
+    fn precedence(&self) -> usize {
+        if let Some((_, level, _, _)) = self.as_infix() {
+            return level;
+        }
+        match &self.kind {
+            ExprKind::If(..) | ExprKind::Let(..) | ExprKind::Lambda(..) | ExprKind::Pi(..) | ExprKind::Match(..) => PREC_IF,
+            ExprKind::Tag(_, x) if !x.is_empty_array() => PREC_APPLICATION,
+            ExprKind::Int(n) if n.sign() == Sign::Minus => PREC_PREFIX,
+            ExprKind::Call(..) if self.as_neg().is_some() => PREC_PREFIX,
+            ExprKind::Call(_, hs, xs) if !hs.is_empty() || !xs.is_empty() => PREC_APPLICATION,
+            ExprKind::Apply(..) => PREC_APPLICATION,
+            _ => PREC_TIGHT,
+        }
+    }
+
+    /// Writes the expression so that it parses back the same way in a position that only accepts
+    /// expressions of at least the given precedence, adding brackets if needed.
+    fn fmt_at(&self, f: &mut fmt::Formatter, prec: usize) -> fmt::Result {
+        if self.precedence() < prec {
+            write!(f, "(")?;
+            self.fmt_at(f, PREC_IF)?;
+            return write!(f, ")");
+        }
+        if let Some((op, level, a, b)) = self.as_infix() {
+            // Operators associate to the left
+            a.fmt_at(f, level)?;
+            write!(f, " {} ", op)?;
+            return b.fmt_at(f, level + 1);
+        }
+        if let Some(x) = self.as_neg() {
+            write!(f, "-")?;
+            return x.fmt_at(f, PREC_PREFIX);
+        }
+        match &self.kind {
+            ExprKind::Int(n) => write!(f, "{}", n),
+            ExprKind::Str(s) => write_string(f, s),
+            ExprKind::Var(x) => write!(f, "{}", x),
+            ExprKind::Call(g, hs, xs) => {
+                write!(f, "{}", g)?;
+                for (h, x) in hs {
+                    write!(f, " {{{}=", h)?;
+                    x.fmt_at(f, PREC_IF)?;
+                    write!(f, "}}")?;
+                }
+                write_args(f, xs)
+            }
+            ExprKind::Apply(g, xs) => {
+                if let ExprKind::Var(_) | ExprKind::Tag(..) = g.kind {
+                    // Otherwise it would be read as a call or a tag with a value
+                    write!(f, "({})", g)?;
+                } else {
+                    g.fmt_at(f, PREC_TIGHT)?;
+                }
+                write_args(f, xs)
+            }
+            ExprKind::Array(xs) => {
+                write!(f, "[")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    x.fmt_at(f, PREC_TIGHT)?;
+                }
+                write!(f, "]")
+            }
+            ExprKind::If(c, a, b) => write!(f, "if {} then {} else {}", c, a, b),
+            ExprKind::Let(x, t, v, b) => {
+                write!(f, "let {}", x)?;
+                if let Some(t) = t {
+                    write!(f, " : {}", t)?;
+                }
+                write!(f, " = {} in {}", v, b)
+            }
+            ExprKind::Lambda(x, t, b) => write!(f, "\\({}:{}) -> {}", x, t, b),
+            ExprKind::Pi(x, a, b) if x == "_" => {
+                // The arrow associates to the right
+                a.fmt_at(f, PREC_IF + 1)?;
+                write!(f, " -> {}", b)
+            }
+            ExprKind::Pi(x, a, b) => write!(f, "pi ({}:{}) -> {}", x, a, b),
+            ExprKind::RecordType(fs) => write_fields(f, fs, ":"),
+            ExprKind::Record(fs) => write_fields(f, fs, " = "),
+            ExprKind::SumType(fs) => {
+                // With fewer than two tags, the leading bar is what says it isn't a record type
+                write!(f, "{{{}", match fs.len() { 0 => "|", 1 => "| ", _ => "" })?;
+                for (i, (a, t)) in fs.iter().enumerate() {
+                    write!(f, "{}{}", if i > 0 { " | " } else { "" }, a)?;
+                    // A tag that carries nothing in particular
+                    if t.kind != ExprKind::Var("true".to_owned()) {
+                        write!(f, ":{}", t)?;
+                    }
+                }
+                write!(f, "}}")
+            }
+            ExprKind::Tag(a, x) if x.is_empty_array() => write!(f, "#{}", a),
+            ExprKind::Tag(a, x) => {
+                write!(f, "#{} ", a)?;
+                x.fmt_at(f, PREC_TIGHT)
+            }
+            ExprKind::Field(r, x) => {
+                r.fmt_at(f, PREC_TIGHT)?;
+                write!(f, ".{}", x)
+            }
+            ExprKind::Match(x, arms) => {
+                write!(f, "match {} {{", x)?;
+                for (i, (a, y, b)) in arms.iter().enumerate() {
+                    write!(f, "{}#{}", if i > 0 { ", " } else { "" }, a)?;
+                    if y != "_" {
+                        write!(f, " {}", y)?;
+                    }
+                    write!(f, " -> {}", b)?;
+                }
+                write!(f, "}}")
+            }
+            ExprKind::And(..) | ExprKind::Or(..) => unreachable!("always written as infix"),
+        }
+    }
+}
+
+/// Writes the arguments of a call, each preceded by a space
+fn write_args(f: &mut fmt::Formatter, xs: &[Expr]) -> fmt::Result {
+    for x in xs {
+        write!(f, " ")?;
+        if x.starts_with_record() {
+            // Otherwise `f {x = 1}` would be read as a hidden argument
+            write!(f, "({})", x)?;
+        } else {
+            x.fmt_at(f, PREC_TIGHT)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a record or record type, whose fields are separated by commas
+fn write_fields(f: &mut fmt::Formatter, fs: &[(String, Expr)], sep: &str) -> fmt::Result {
+    write!(f, "{{")?;
+    for (i, (x, e)) in fs.iter().enumerate() {
+        write!(f, "{}{}{}{}", if i > 0 { ", " } else { "" }, x, sep, e)?;
+    }
+    write!(f, "}}")
+}
+
+/// Writes a string literal, escaping anything the parser would otherwise misread
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            '\0' => write!(f, "\\0")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// Prints surface syntax
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_at(f, PREC_IF)
+    }
+}
+
+/// Prints the part of a declaration line after the name, e.g. `{t:type} (x:t) : t`
+impl fmt::Display for Sig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (h, t) in &self.hidden {
+            write!(f, "{{{}:{}}} ", h, t)?;
+        }
+        for (x, t) in &self.args {
+            write!(f, "({}:{}) ", x, t)?;
+        }
+        write!(f, ": {}", self.ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    /// At least one of each kind of expression, along with the places brackets are needed
+    const EXAMPLES: &[&str] = &[
+        "1",
+        "-3",
+        r#""a \"quoted\"\n\\ string""#,
+        "x",
+        "f {t=int} x (g y) [1 2]",
+        "[1 (f x) (-2) [3]]",
+        "if a then b else c",
+        "(if a then b else c) + 1",
+        "let x : int = 1 in x + x",
+        "let x = 1 in let y = x in y",
+        "\\(x:int) -> x + 1",
+        "f (\\(x:int) -> x)",
+        "pi (n:uint) -> vector int n",
+        "int -> int -> int",
+        "(int -> int) -> int",
+        "{x:int, y:vector int x}",
+        "{a:int | b | c:list int}",
+        "{| a:int}",
+        "{|}",
+        "{x = 1, y = f 2}",
+        "g ({x = 1})",
+        "g ({x = 1, y = 2}) ({z = 3}.z)",
+        "#a 3",
+        "#b",
+        "f (#a 3) #b",
+        "#a (f x)",
+        "r.x",
+        "(f r).x.y",
+        "(\\(x:int) -> x) 3",
+        "(f 1) 2 (g 3)",
+        "(x) 1",
+        "r.f 1 ({x = 2})",
+        "(#a) 1",
+        "match v {#a n -> n + 1, #b -> 0}",
+        "(match v {#a -> 1}) + 1",
+        "x && (x || x)",
+        "(a || a) == a",
+        "a || b && c",
+        "(a || b) && c",
+        "(a && b) && c",
+        "a && (b && c)",
+        "a - (b - c)",
+        "(a - b) - c",
+        "-(a + b)",
+        "- -a",
+        "a * (b + c) < d",
+    ];
+
+    #[test]
+    fn printed_expressions_parse_back_the_same() {
+        for text in EXAMPLES {
+            let expr = parse::parse_expr(text, 0).unwrap();
+            let printed = expr.to_string();
+            let reparsed = parse::parse_expr(&printed, 0).unwrap_or_else(|e| panic!("{} printed as {}: {}", text, printed, e));
+            assert_eq!(reparsed, expr, "{} printed as {}", text, printed);
+        }
+    }
+
+    /// Expressions built by the type checker rather than parsed only get the brackets they need
+    #[test]
+    fn built_expressions_get_minimal_brackets() {
+        let (a, b, c) = (Expr::var("a"), Expr::var("b"), Expr::var("c"));
+        let add = |x: &Expr, y: &Expr| Expr::call("add", vec![x.clone(), y.clone()]);
+        let mul = |x: &Expr, y: &Expr| Expr::call("mul", vec![x.clone(), y.clone()]);
+        assert_eq!(add(&mul(&a, &b), &c).to_string(), "a * b + c");
+        assert_eq!(mul(&add(&a, &b), &c).to_string(), "(a + b) * c");
+        assert_eq!(add(&a, &add(&b, &c)).to_string(), "a + (b + c)");
+        assert_eq!(Expr::call("vector", vec![Expr::var("int"), add(&a, &b)]).to_string(), "vector int (a + b)");
+        assert_eq!(Expr::call("tuple", vec![ExprKind::Array(vec![Expr::call("list", vec![a]), ExprKind::Str("\n".into()).into()]).into()]).to_string(), "tuple [(list a) \"\\n\"]");
+    }
+
+    #[test]
+    fn logical_operators_are_bracketed() {
+        assert_eq!(parse::parse_expr("x && (x || x)", 0).unwrap().to_string(), "x && (x || x)");
+        assert_eq!(parse::parse_expr("(a || a) == a", 0).unwrap().to_string(), "(a || a) == a");
+        assert_eq!(parse::parse_expr("(a && b) || c", 0).unwrap().to_string(), "a && b || c");
+    }
+
+    #[test]
+    fn substitution_renames_binders_that_would_capture() {
+        let e = |text: &str| parse::parse_expr(text, 0).unwrap();
+        let sub = |text: &str, vars: &[(&str, &str)]| {
+            let vars = vars.iter().map(|(x, v)| (x.to_string(), e(v))).collect();
+            e(text).substitute(&vars).to_string()
+        };
+        let y = [("y", "x + 1")];
+        assert_eq!(sub("\\(x:int) -> x + y", &y), "\\(x':int) -> x' + (x + 1)");
+        assert_eq!(sub("let x = 2 in x * y", &y), "let x' = 2 in x' * (x + 1)");
+        assert_eq!(sub("pi (x:uint) -> vector int (x + y)", &y), "pi (x':uint) -> vector int (x' + (x + 1))");
+        assert_eq!(sub("match t {#a x -> x + y}", &y), "match t {#a x' -> x' + (x + 1)}");
+        // Binders are only renamed when something would be captured
+        assert_eq!(sub("\\(z:int) -> z + y", &y), "\\(z:int) -> z + (x + 1)");
+        assert_eq!(sub("\\(y:int) -> x + y", &y), "\\(y:int) -> x + y");
+        assert_eq!(sub("f g", &[("f", "\\(n:int) -> n"), ("g", "f")]), "let f' = \\(n:int) -> n in f' f");
+    }
 }
@@ -1,123 +1,407 @@
 use std::collections::HashMap;
 use std::fmt;
 
-use crate::ast::{Script, Expr};
+use crate::ast::{Decl, Script, Expr, ExprKind, Sig, Span};
 
 #[derive(Debug)]
 pub enum CombineError {
-    DuplicateDecl(String),
-    NoSuchDecl(String),
-    Recursion(String),
+    DuplicateDecl(String, Span),
+    /// The span is where the missing name was used
+    NoSuchDecl(String, Option<Span>),
+    NoBody(String, Span),
+    MismatchedArgCount(String, usize, usize, Span),
+    /// A script declaring a name that the standard library already uses
+    Reserved(String, Span),
 }
 
 #[derive(Clone, Debug)]
 pub struct Program {
-    pub order: Vec<String>,
+    /// Strongly connected components of the dependency graph. Each component comes after everything
+    /// it depends on, and contains more than one function only if they are mutually recursive.
+    pub order: Vec<Vec<String>>,
     pub funcs: HashMap<String, Func>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Func {
-    pub args: Vec<(String, Expr)>,
-    pub ret: Expr,
+    pub sigs: Vec<Sig>,
+    /// The argument names as written on the line with the body
+    pub arg_names: Vec<String>,
+    /// The hidden argument names as written on the line with the body
+    pub hidden_names: Vec<String>,
     pub body: Expr,
     pub prelude: bool,
+    /// The line with the body
+    pub span: Span,
 }
 
 enum Visited {
-    Visiting,
+    /// On the stack, with the given discovery index
+    Visiting(usize),
     Visited,
 }
 
-pub fn combine(prelude_script: &Script, main_script: &Script) -> Result<Program, CombineError> {
-    let mut funcs = HashMap::new();
+/// Every duplicate, missing or inconsistent declaration is reported, in the order they appear
+pub fn combine(prelude_script: &Script, main_script: &Script) -> Result<Program, Vec<CombineError>> {
+    let mut errors = vec![];
+
+    // All the lines declaring a given name are gathered together, in the order they were written
+    let mut names = vec![];
+    let mut decls = HashMap::new();
     for ((name, decl), prelude) in prelude_script.decls.iter().map(|d|(d,true)).chain(main_script.decls.iter().map(|d|(d,false))) {
-        if funcs.contains_key(name) {
-            return Err(CombineError::DuplicateDecl(name.clone()));
+        match decls.get_mut(name) {
+            None => {
+                names.push((name.clone(), prelude));
+                decls.insert(name.clone(), (prelude, vec![decl]));
+            }
+            Some((p, _)) if *p != prelude => errors.push(CombineError::DuplicateDecl(name.clone(), decl.span)),
+            Some((_, ds)) => ds.push(decl),
+        }
+    }
+
+    let mut funcs = HashMap::new();
+    // Declared names that couldn't be made into functions. These have already been reported, so
+    // uses of them aren't reported as well.
+    let mut broken = vec![];
+    for (name, prelude) in &names {
+        match combine_decls(name, &decls[name].1, *prelude) {
+            Ok(func) => {
+                funcs.insert(name.clone(), func);
+            }
+            Err(es) => {
+                errors.extend(es);
+                broken.push(name.clone());
+            }
         }
-        funcs.insert(name.clone(), Func {
-            args: decl.args.clone(),
-            ret: decl.ret.clone(),
-            body: decl.body.clone(),
-            prelude
-        });
     }
 
-    let prelude_order:Vec<_> = prelude_script.decls.iter().map(|d|d.0.clone()).collect();
+    let prelude_order:Vec<_> = names.iter().filter(|n| n.1).map(|n| vec![n.0.clone()]).collect();
 
     let mut visits = HashMap::new();
-    for name in &prelude_order {
+    for name in prelude_order.iter().flatten().chain(&broken) {
         visits.insert(name.clone(), Visited::Visited);
     }
     let mut program = Program {
         order: prelude_order,
         funcs,
     };
+    let mut stack = vec![];
     for (name,_) in &main_script.decls {
-        visit_for_ordering(&mut program, name, &mut visits)?;
+        if !visits.contains_key(name) {
+            visit_for_ordering(&mut program, name, &mut visits, &mut stack, &mut errors);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        errors.sort_by_key(|e| e.span().map(|span| span.start));
+        Err(errors)
     }
-    Ok(program)
 }
 
-fn visit_for_ordering(program: &mut Program, name: &str, visits: &mut HashMap<String,Visited>) -> Result<(), CombineError> {
-    if let Some(v) = visits.get(name) {
-        match v {
-            Visited::Visited => Ok(()),
-            Visited::Visiting => Err(CombineError::Recursion(name.to_owned())),
-        }
+/// The standard library gets combined as part of the main script, so without this a script could
+/// add signatures to its functions or clash with their bodies. Reports every declaration of a
+/// standard library name.
+pub fn check_not_reserved(stdlib: &Script, script: &Script) -> Result<(), Vec<CombineError>> {
+    let errors: Vec<_> = script.decls.iter()
+        .filter(|(name, _)| stdlib.decls.iter().any(|(s, _)| s == name))
+        .map(|(name, d)| CombineError::Reserved(name.clone(), d.span))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
     } else {
-        visits.insert(name.to_owned(), Visited::Visiting);
-        for dep in &get_dependencies(program, name)? {
-            visit_for_ordering(program, dep, visits)?;
+        Err(errors)
+    }
+}
+
+/// Exactly one of the lines must have a body, and they must all have the same number of arguments
+fn combine_decls(name: &str, ds: &[&Decl], prelude: bool) -> Result<Func, Vec<CombineError>> {
+    let mut errors = vec![];
+    let mut bodies = ds.iter().filter(|d| d.body.is_some());
+    let body_decl = bodies.next().ok_or_else(|| vec![CombineError::NoBody(name.to_owned(), ds[0].span)])?;
+    for d in bodies {
+        errors.push(CombineError::DuplicateDecl(name.to_owned(), d.span));
+    }
+    let body_sig = &body_decl.sig;
+    for d in ds {
+        if d.sig.args.len() != body_sig.args.len() {
+            errors.push(CombineError::MismatchedArgCount(name.to_owned(), body_sig.args.len(), d.sig.args.len(), d.span));
         }
-        visits.insert(name.to_owned(), Visited::Visited);
-        program.order.push(name.to_owned());
-        Ok(())
     }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Func {
+        sigs: ds.iter().map(|d| d.sig.clone()).collect(),
+        arg_names: body_sig.args.iter().map(|a| a.0.clone()).collect(),
+        hidden_names: body_sig.hidden.iter().map(|a| a.0.clone()).collect(),
+        body: body_decl.body.clone().unwrap(),
+        prelude,
+        span: body_decl.span,
+    })
 }
 
-fn get_dependencies(program: &Program, name: &str) -> Result<Vec<String>, CombineError> {
-    if let Some(func) = program.funcs.get(name) {
-        let mut result = vec![];
-        for arg in &func.args {
-            add_dependencies(&arg.1, &mut result);
+/// Tarjan's strongly connected components algorithm. Returns the lowest discovery index reachable
+/// from `name` through functions that are still on the stack. Missing dependencies are added to
+/// `errors` and otherwise ignored.
+fn visit_for_ordering(program: &mut Program, name: &str, visits: &mut HashMap<String,Visited>, stack: &mut Vec<String>, errors: &mut Vec<CombineError>) -> usize {
+    let index = visits.len();
+    visits.insert(name.to_owned(), Visited::Visiting(index));
+    stack.push(name.to_owned());
+
+    let mut lowlink = index;
+    for (dep, span) in get_dependencies(&program.funcs[name]) {
+        let dep_lowlink = match visits.get(&dep) {
+            None if !program.funcs.contains_key(&dep) => {
+                errors.push(CombineError::NoSuchDecl(dep, span));
+                continue;
+            }
+            None => visit_for_ordering(program, &dep, visits, stack, errors),
+            Some(Visited::Visiting(i)) => *i,
+            Some(Visited::Visited) => continue,
+        };
+        lowlink = lowlink.min(dep_lowlink);
+    }
+
+    if lowlink == index {
+        let mut component = vec![];
+        loop {
+            let member = stack.pop().expect("name should still be on the stack");
+            visits.insert(member.clone(), Visited::Visited);
+            let done = member == name;
+            component.push(member);
+            if done {
+                break;
+            }
+        }
+        component.reverse();
+        program.order.push(component);
+    }
+    lowlink
+}
+
+/// Each dependency is listed once, along with the first place it was used
+fn get_dependencies(func: &Func) -> Vec<(String, Option<Span>)> {
+    let mut result: Vec<(String, Option<Span>)> = vec![];
+    for sig in &func.sigs {
+        let mut sig_deps = vec![];
+        for arg in sig.hidden.iter().chain(&sig.args) {
+            add_dependencies(&arg.1, &mut sig_deps);
+        }
+        add_dependencies(&sig.ret, &mut sig_deps);
+        for dep in sig_deps {
+            if !result.iter().any(|r| r.0 == dep.0) && !sig.hidden.iter().chain(&sig.args).any(|arg| arg.0 == dep.0) {
+                result.push(dep);
+            }
+        }
+    }
+    let mut body_deps = vec![];
+    add_dependencies(&func.body, &mut body_deps);
+    for dep in body_deps {
+        if !result.iter().any(|r| r.0 == dep.0) && !func.arg_names.contains(&dep.0) && !func.hidden_names.contains(&dep.0) {
+            result.push(dep);
         }
-        add_dependencies(&func.ret, &mut result);
-        add_dependencies(&func.body, &mut result);
-        Ok(result)
-    } else {
-        Err(CombineError::NoSuchDecl(name.to_owned()))
     }
+    result
 }
 
-fn add_dependencies(expr: &Expr, result: &mut Vec<String>) {
-    match expr {
-        Expr::Int(_) => {}
-        Expr::Var(x) => {
-            if !result.contains(x) {
-                result.push(x.clone());
+/// The functions and variables that an expression refers to, other than its own locals
+pub fn free_names(expr: &Expr) -> Vec<String> {
+    let mut result = vec![];
+    add_dependencies(expr, &mut result);
+    result.into_iter().map(|r| r.0).collect()
+}
+
+fn add_dependencies(expr: &Expr, result: &mut Vec<(String, Option<Span>)>) {
+    match &expr.kind {
+        ExprKind::Int(_) | ExprKind::Str(_) => {}
+        ExprKind::Var(x) => {
+            if !result.iter().any(|r| &r.0 == x) {
+                result.push((x.clone(), expr.span));
             }
         }
-        Expr::Call(f,xs) => {
-            if !result.contains(f) {
-                result.push(f.clone());
+        ExprKind::Call(f,hs,xs) => {
+            if !result.iter().any(|r| &r.0 == f) {
+                result.push((f.clone(), expr.span));
             }
+            for x in hs.iter().map(|h|&h.1).chain(xs) {
+                add_dependencies(x, result);
+            }
+        }
+        ExprKind::Apply(g, xs) => {
+            add_dependencies(g, result);
             for x in xs {
                 add_dependencies(x, result);
             }
         }
-        Expr::Array(xs) => {
+        ExprKind::Array(xs) => {
             for x in xs {
                 add_dependencies(x, result);
             }
         }
+        ExprKind::If(c, a, b) => {
+            add_dependencies(c, result);
+            add_dependencies(a, result);
+            add_dependencies(b, result);
+        }
+        ExprKind::Let(x, t, v, b) => {
+            if let Some(t) = t {
+                add_dependencies(t, result);
+            }
+            add_dependencies(v, result);
+            add_bound_dependencies(x, b, result);
+        }
+        ExprKind::Lambda(x, t, b) | ExprKind::Pi(x, t, b) => {
+            add_dependencies(t, result);
+            add_bound_dependencies(x, b, result);
+        }
+        // Each field's type can mention the fields before it
+        ExprKind::RecordType(fs) => {
+            for (i, (_, t)) in fs.iter().enumerate() {
+                let mut field_deps = vec![];
+                add_dependencies(t, &mut field_deps);
+                for dep in field_deps {
+                    if !fs[..i].iter().any(|f| f.0 == dep.0) && !result.iter().any(|r| r.0 == dep.0) {
+                        result.push(dep);
+                    }
+                }
+            }
+        }
+        ExprKind::SumType(fs) | ExprKind::Record(fs) => {
+            for (_, x) in fs {
+                add_dependencies(x, result);
+            }
+        }
+        ExprKind::Tag(_, x) | ExprKind::Field(x, _) => add_dependencies(x, result),
+        ExprKind::Match(x, arms) => {
+            add_dependencies(x, result);
+            for (_, y, b) in arms {
+                add_bound_dependencies(y, b, result);
+            }
+        }
+        ExprKind::And(a, b) | ExprKind::Or(a, b) => {
+            add_dependencies(a, result);
+            add_dependencies(b, result);
+        }
+    }
+}
+
+/// Inside the body, the name refers to the local variable rather than a declaration
+fn add_bound_dependencies(x: &str, body: &Expr, result: &mut Vec<(String, Option<Span>)>) {
+    let mut body_deps = vec![];
+    add_dependencies(body, &mut body_deps);
+    for dep in body_deps {
+        if dep.0 != x && !result.iter().any(|r| r.0 == dep.0) {
+            result.push(dep);
+        }
+    }
+}
+
+impl CombineError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CombineError::DuplicateDecl(_, span) | CombineError::NoBody(_, span) | CombineError::MismatchedArgCount(_, _, _, span) | CombineError::Reserved(_, span) => Some(*span),
+            CombineError::NoSuchDecl(_, span) => *span,
+        }
     }
 }
 
 impl fmt::Display for CombineError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Combine error {:?}", self)
+        match self {
+            CombineError::DuplicateDecl(name, _) => write!(f, "{} has already been declared", name),
+            CombineError::NoSuchDecl(name, _) => write!(f, "nothing called {} has been declared", name),
+            CombineError::NoBody(name, _) => write!(f, "{} is declared but never given a body", name),
+            CombineError::MismatchedArgCount(name, expected, got, _) => write!(f, "{} has {} arguments on the line with the body, but {} here", name, expected, got),
+            CombineError::Reserved(name, _) => write!(f, "{} is reserved by the standard library", name),
+        }
     }
 }
 
 impl std::error::Error for CombineError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    fn combine_script(script: &str) -> Result<Program, Vec<CombineError>> {
+        let prelude = include_str!("prelude.jd");
+        combine(&parse::parse(prelude, 0).unwrap(), &parse::parse(script, prelude.len() + 1).unwrap())
+    }
+
+    /// The components of the user's functions, in order, with each component sorted
+    fn user_order(program: &Program) -> Vec<Vec<String>> {
+        program.order.iter()
+            .filter(|c| !program.funcs[&c[0]].prelude)
+            .map(|c| {
+                let mut c = c.clone();
+                c.sort();
+                c
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mutually_recursive_functions_share_a_component() {
+        let script = "
+            main (n:int) : bool = even n;
+            even (n:int) : bool = if n <= 0 then true else odd (n - 1);
+            odd (n:int) : bool = if n <= 0 then false else even (n - 1);
+            fact (n:int) : int = if n <= 0 then 1 else n * fact (n - 1);
+        ";
+        let program = combine_script(script).unwrap();
+        assert_eq!(user_order(&program), [vec!["even", "odd"], vec!["main"], vec!["fact"]]);
+    }
+
+    #[test]
+    fn functions_come_after_what_their_signatures_use() {
+        let script = "
+            f (xs:pair) : int = 0;
+            pair : type = tuple [int int];
+        ";
+        let program = combine_script(script).unwrap();
+        assert_eq!(user_order(&program), [vec!["pair"], vec!["f"]]);
+    }
+
+    /// Every error from combining a script that shouldn't combine
+    fn errors(script: &str) -> Vec<String> {
+        combine_script(script).unwrap_err().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn missing_functions_are_reported() {
+        assert_eq!(errors("f (x:int) : int = g x;"), ["nothing called g has been declared"]);
+    }
+
+    #[test]
+    fn extra_signatures_must_agree_with_the_body() {
+        assert_eq!(errors("f (x:int) : int = x;\nf (x:uint) : uint = x;"), ["f has already been declared"]);
+        assert_eq!(errors("f (x:int) : int = x;\nf (x:uint) (y:uint) : uint;"), ["f has 1 arguments on the line with the body, but 2 here"]);
+        assert_eq!(errors("f (x:int) : int;\ng : int = 0;"), ["f is declared but never given a body"]);
+        assert_eq!(errors("add (x:int) : int = x;"), ["add has already been declared"]);
+        let program = combine_script("f (x:uint) : uint;\nf (y:int) : int = y;").unwrap();
+        assert_eq!(program.funcs["f"].sigs.len(), 2);
+        assert_eq!(program.funcs["f"].arg_names, ["y"]);
+    }
+
+    #[test]
+    fn every_bad_declaration_is_reported() {
+        let script = "
+            f : int = 0;
+            f : int = 1;
+            g : int = x;
+            h : int;
+            k : int = 2;
+            k : int = y;
+        ";
+        // `y` isn't reported since the function it's in is already a duplicate
+        assert_eq!(errors(script), [
+            "f has already been declared",
+            "nothing called x has been declared",
+            "h is declared but never given a body",
+            "k has already been declared",
+        ]);
+    }
+}
@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use num_bigint::BigInt;
+use num_bigint::{BigInt, Sign};
 use num_traits::cast::ToPrimitive;
+use num_traits::Zero;
 
-use crate::ast::Expr;
-use crate::combine::{Program, Func};
+use crate::ast::{Expr, ExprKind, Span};
+use crate::combine::{self, Program, Func};
 
-#[derive(Clone, Debug)]
+/// `Type::Type` is the type of types, so its name isn't redundant
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Type {
     False,
     True,
@@ -17,15 +20,50 @@ pub enum Type {
     List(Box<Type>),
     Vector(Box<Type>, usize),
     Tuple(Vec<Type>),
+    /// Types with a computable equality
+    Eq,
     Type,
+    /// A function type. The closure gives the result type in terms of the parameter.
+    Pi(Box<Closure>),
+    /// The types of the fields are kept as expressions, see `Fields`
+    Record(Box<Fields>),
+    /// The type of the value that goes with each tag
+    Sum(Vec<(String, Type)>),
+    /// What a hidden argument of type `type` stands for in checked mode, since the interpreter
+    /// doesn't know its value. Every value has this type.
+    Unknown,
 }
 
-#[derive(Clone, Debug)]
+/// Equality here is structural, which is what `equals` computes
+#[derive(Clone, Debug, PartialEq)]
 pub enum Val {
     Int(BigInt),
     String(String),
     Array(Vec<Val>),
     Type(Type),
+    Func(Box<Closure>),
+    /// Kept in order of name, so that the order the fields are written in doesn't matter
+    Record(BTreeMap<String, Val>),
+    Tag(String, Box<Val>),
+}
+
+/// A lambda along with the local variables it can see. Also used for the result type of a
+/// function type, since that can depend on the parameter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Closure {
+    pub param: String,
+    /// Only kept for printing, since the interpreter doesn't check argument types
+    pub param_type: Expr,
+    pub body: Expr,
+    pub env: HashMap<String, Val>,
+}
+
+/// The fields of a record type. A field's type can depend on the fields before it, so the types
+/// are kept as expressions, along with the other local variables they use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fields {
+    pub types: Vec<(String, Expr)>,
+    pub env: HashMap<String, Val>,
 }
 
 #[derive(Debug)]
@@ -37,9 +75,163 @@ pub enum EvalError {
     NotInteger(Val),
     NotType(Val),
     NotArray(Val),
+    NotBool(Val),
+    NotFunction(Val),
+    NotRecord(Val),
+    NoSuchField(String, Val),
+    NotTag(Val),
+    UnmatchedTag(String),
+    DivisionByZero,
+    NotString(Val),
+    IndexOutOfBounds(usize, usize),
+    InvalidInt(String),
+    /// The call stack at the point the fuel ran out, outermost first
+    OutOfFuel(Vec<String>),
+    /// The call stack that got too deep, outermost first
+    StackDepthExceeded(Vec<String>),
+    /// Function name, argument name, the value and the type it should have had
+    BadArgument(String, String, Val, Type),
+    /// Function name, the value returned and the type it should have had
+    BadReturnValue(String, Val, Type),
+    /// The innermost expression being evaluated when the error happened
+    At(Span, Box<EvalError>),
+}
+
+/// Mutable state threaded through evaluation
+pub struct EvalState {
+    /// Memoized values of zero-argument functions
+    pub global_env: HashMap<String, Val>,
+    /// How many more expressions may be evaluated, if limited
+    pub fuel: Option<u64>,
+    /// How deeply function calls may be nested, if limited. Calls recurse on the native stack, so
+    /// without a limit a deep enough recursion will crash the interpreter.
+    pub max_depth: Option<usize>,
+    /// The functions currently being called, outermost first
+    pub call_stack: Vec<String>,
+    /// Whether calls check their arguments and results against the declared signatures, and
+    /// lambdas their arguments against the parameter type
+    pub checked: bool,
+    /// The native stack address that evaluation mustn't go below, see `limit_stack`
+    pub stack_limit: Option<usize>,
+}
+
+impl EvalState {
+    pub fn new(fuel: Option<u64>) -> Self {
+        EvalState {
+            global_env: HashMap::new(),
+            fuel,
+            max_depth: None,
+            call_stack: vec![],
+            checked: false,
+            stack_limit: None,
+        }
+    }
+
+    /// Each expression evaluated costs one unit of fuel, and each element of an array a builtin
+    /// creates costs another
+    fn spend_fuel(&mut self, amount: usize) -> Result<(), EvalError> {
+        if let Some(fuel) = &mut self.fuel {
+            match fuel.checked_sub(amount as u64) {
+                Some(rest) => *fuel = rest,
+                None => return Err(EvalError::OutOfFuel(self.call_stack.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes evaluation stop with `StackDepthExceeded` rather than use more than `size` bytes of
+    /// native stack below the caller. `max_depth` normally stops it first, but a function body
+    /// whose expressions nest deeply uses more stack for each call.
+    pub fn limit_stack(&mut self, size: usize) {
+        self.stack_limit = Some(stack_position().saturating_sub(size));
+    }
+}
+
+/// Roughly where the top of the native stack is. The stack grows downwards.
+///
+/// Comparing two of these only means something on the same thread, where every frame of a
+/// nested call sits below its caller's in one contiguous stack. That holds here: `limit_stack`
+/// and the check in `eval_here` both run on the thread that evaluates, and the state never moves
+/// between threads after `limit_stack`. The address of a local is off by at most one frame, which
+/// the half of `BASE_STACK` left over in `main` more than covers. `inline(never)` keeps the local
+/// in a frame of its own, below whichever frame is asking.
+#[inline(never)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+impl Type {
+    pub fn to_expr(&self) -> Expr {
+        let label = Expr::var;
+        let call = Expr::call;
+        match self {
+            Type::False => label("false"),
+            Type::True => label("true"),
+            Type::Bool => label("bool"),
+            Type::Int => label("int"),
+            Type::Uint => label("uint"),
+            Type::String => label("string"),
+            Type::List(t) => call("list", vec![t.to_expr()]),
+            Type::Vector(t, n) => call("vector", vec![t.to_expr(), ExprKind::Int((*n).into()).into()]),
+            Type::Tuple(ts) => call("tuple", vec![ExprKind::Array(ts.iter().map(Type::to_expr).collect()).into()]),
+            Type::Eq => label("eq"),
+            Type::Type => label("type"),
+            Type::Pi(c) => c.to_expr(ExprKind::Pi),
+            Type::Record(fs) => {
+                let vars = fs.env.iter().map(|(x, v)| (x.clone(), v.to_expr())).collect();
+                Expr::from(ExprKind::RecordType(fs.types.clone())).substitute(&vars)
+            }
+            Type::Sum(ts) => ExprKind::SumType(ts.iter().map(|(a, t)| (a.clone(), t.to_expr())).collect()).into(),
+            Type::Unknown => label("_"),
+        }
+    }
+}
+
+impl Closure {
+    /// The captured variables are written out as their values
+    fn to_expr(&self, make: fn(String, Box<Expr>, Box<Expr>) -> ExprKind) -> Expr {
+        let vars = self.env.iter().map(|(x, v)| (x.clone(), v.to_expr())).collect();
+        Expr::from(make(self.param.clone(), Box::new(self.param_type.clone()), Box::new(self.body.clone()))).substitute(&vars)
+    }
+}
+
+/// Prints the expression that evaluates to this type
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_expr().fmt(f)
+    }
+}
+
+/// Prints the expression that evaluates to this value
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_expr().fmt(f)
+    }
 }
 
 impl Val {
+    fn from_bool(b: bool) -> Val {
+        if b {
+            Val::Type(Type::True)
+        } else {
+            Val::Type(Type::False)
+        }
+    }
+
+    /// Converts back to an expression that evaluates to this value
+    pub fn to_expr(&self) -> Expr {
+        match self {
+            Val::Int(n) => ExprKind::Int(n.clone()).into(),
+            Val::String(s) => ExprKind::Str(s.clone()).into(),
+            Val::Array(xs) => ExprKind::Array(xs.iter().map(Val::to_expr).collect()).into(),
+            Val::Type(t) => t.to_expr(),
+            Val::Func(c) => c.to_expr(ExprKind::Lambda),
+            Val::Record(vs) => ExprKind::Record(vs.iter().map(|(x, v)| (x.clone(), v.to_expr())).collect()).into(),
+            Val::Tag(a, v) => ExprKind::Tag(a.clone(), Box::new(v.to_expr())).into(),
+        }
+    }
+
     fn unwrap_usize(&self) -> Result<usize, EvalError> {
         match self {
             Val::Int(i) => match i.to_usize() {
@@ -50,7 +242,38 @@ impl Val {
         }
     }
 
-    fn unwrap_type(&self) -> Result<Type, EvalError> {
+    fn unwrap_int(&self) -> Result<&BigInt, EvalError> {
+        match self {
+            Val::Int(i) => Ok(i),
+            _ => Err(EvalError::NotInteger(self.clone())),
+        }
+    }
+
+    fn unwrap_nonzero_int(&self) -> Result<&BigInt, EvalError> {
+        let i = self.unwrap_int()?;
+        if i.is_zero() {
+            Err(EvalError::DivisionByZero)
+        } else {
+            Ok(i)
+        }
+    }
+
+    fn unwrap_string(&self) -> Result<&str, EvalError> {
+        match self {
+            Val::String(s) => Ok(s),
+            _ => Err(EvalError::NotString(self.clone())),
+        }
+    }
+
+    fn unwrap_bool(&self) -> Result<bool, EvalError> {
+        match self {
+            Val::Type(Type::True) => Ok(true),
+            Val::Type(Type::False) => Ok(false),
+            _ => Err(EvalError::NotBool(self.clone())),
+        }
+    }
+
+    pub fn unwrap_type(&self) -> Result<Type, EvalError> {
         match self {
             Val::Type(t) => Ok(t.clone()),
             _ => Err(EvalError::NotType(self.clone())),
@@ -70,13 +293,94 @@ impl Val {
 }
 
 impl Program {
-    fn lookup_or_compute_value(&self, name: &str, global_env: &mut HashMap<String, Val>, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
+    /// Whether the value inhabits the type. `false` is empty and the only value of type `true`
+    /// is `[]`. There's no telling what a function returns without calling it, so any function is
+    /// taken to have any function type. A record may have more fields than its type lists.
+    ///
+    /// The types of a record's fields can depend on the fields before them, which is why this
+    /// may need to evaluate things.
+    pub fn has_type(&self, x: &Val, typ: &Type, state: &mut EvalState) -> Result<bool, EvalError> {
+        Ok(match (x, typ) {
+            (_, Type::Unknown) => true,
+            (Val::Type(Type::False), Type::Bool) | (Val::Type(Type::True), Type::Bool) => true,
+            (Val::Array(xs), Type::True) => xs.is_empty(),
+            (Val::Int(_), Type::Int) | (Val::String(_), Type::String) | (Val::Type(_), Type::Type) => true,
+            (Val::Int(n), Type::Uint) => n.sign() != Sign::Minus,
+            (Val::Array(xs), Type::List(t)) => self.all_have_types(xs.iter().map(|x| (x, &**t)), state)?,
+            (Val::Array(xs), Type::Vector(t,n)) => xs.len() == *n && self.all_have_types(xs.iter().map(|x| (x, &**t)), state)?,
+            (Val::Array(xs), Type::Tuple(ts)) => xs.len() == ts.len() && self.all_have_types(xs.iter().zip(ts), state)?,
+            (Val::Type(t), Type::Eq) => self.is_eq(t, state)?,
+            (Val::Func(_), Type::Pi(_)) => true,
+            (Val::Record(vs), Type::Record(fs)) => {
+                let mut env = fs.env.clone();
+                for (name, t) in &fs.types {
+                    let v = match vs.get(name) {
+                        Some(v) => v,
+                        None => return Ok(false),
+                    };
+                    let t = self.eval(t, state, &env)?.unwrap_type()?;
+                    if !self.has_type(v, &t, state)? {
+                        return Ok(false);
+                    }
+                    env.insert(name.clone(), v.clone());
+                }
+                true
+            }
+            (Val::Tag(a, v), Type::Sum(ts)) => match ts.iter().find(|t| &t.0 == a) {
+                Some((_, t)) => self.has_type(v, t, state)?,
+                None => false,
+            },
+            _ => false,
+        })
+    }
+
+    fn all_have_types<'a>(&self, pairs: impl IntoIterator<Item = (&'a Val, &'a Type)>, state: &mut EvalState) -> Result<bool, EvalError> {
+        for (x, t) in pairs {
+            if !self.has_type(x, t, state)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether equality is defined on this type, i.e. whether it has type `eq`. A field whose type
+    /// depends on other fields can't be judged without their values, so it gets the benefit of
+    /// the doubt.
+    fn is_eq(&self, typ: &Type, state: &mut EvalState) -> Result<bool, EvalError> {
+        Ok(match typ {
+            Type::False | Type::True | Type::Bool | Type::Int | Type::Uint | Type::String | Type::Unknown => true,
+            Type::List(t) | Type::Vector(t, _) => self.is_eq(t, state)?,
+            Type::Tuple(ts) => self.all_eq(ts.iter(), state)?,
+            Type::Sum(ts) => self.all_eq(ts.iter().map(|t| &t.1), state)?,
+            Type::Record(fs) => {
+                for (i, (_, t)) in fs.types.iter().enumerate() {
+                    if !fs.types[..i].iter().any(|f| t.mentions(&f.0)) && !self.is_eq(&self.eval(t, state, &fs.env)?.unwrap_type()?, state)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Type::Eq | Type::Type | Type::Pi(_) => false,
+        })
+    }
+
+    fn all_eq<'a>(&self, ts: impl Iterator<Item = &'a Type>, state: &mut EvalState) -> Result<bool, EvalError> {
+        for t in ts {
+            if !self.is_eq(t, state)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn lookup_or_compute_value(&self, name: &str, state: &mut EvalState, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
         match env.get(name) {
             Some(x) => Ok(x.clone()),
-            None => match global_env.get(name) {
+            None if self.funcs.get(name).is_some_and(|f| !f.arg_names.is_empty()) => self.func_value(name),
+            None => match state.global_env.get(name) {
                 None => {
-                    let x = self.call(name, &[], global_env)?;
-                    global_env.insert(name.to_owned(), x.clone());
+                    let x = self.call(name, &[], state)?;
+                    state.global_env.insert(name.to_owned(), x.clone());
                     Ok(x)
                 }
                 Some(x) => Ok(x.clone()),
@@ -84,6 +388,21 @@ impl Program {
         }
     }
 
+    /// A function used as a value becomes a lambda for each argument, with a call to the function
+    /// in the innermost body, so `f` taking two arguments is `\(x:a) -> \(y:b) -> f x y`
+    fn func_value(&self, name: &str) -> Result<Val, EvalError> {
+        let func = self.lookup_fn(name)?;
+        let params = &func.sigs[0].args;
+        let call = Expr::call(name, params.iter().map(|(x, _)| Expr::var(x)).collect());
+        let body = params[1..].iter().rev().fold(call, |b, (x, t)| ExprKind::Lambda(x.clone(), Box::new(t.clone()), Box::new(b)).into());
+        Ok(Val::Func(Box::new(Closure {
+            param: params[0].0.clone(),
+            param_type: params[0].1.clone(),
+            body,
+            env: HashMap::new(),
+        })))
+    }
+
     fn lookup_fn(&self, name: &str) -> Result<&Func, EvalError> {
         match self.funcs.get(name) {
             None => Err(EvalError::NoSuchFunc(name.to_owned())),
@@ -91,79 +410,690 @@ impl Program {
         }
     }
 
-    /*
-    fn has_type(&self, value: &Val, typ: &Type) -> bool {
-        match (value, typ) {
-            (Val::Type(Type::False), Type::Bool) | (Val::Type(Type::True), Type::Bool) | (Val::Int(_), Type::Int) | (Val::String(_), Type::String) | (Val::Type(_), Type::Type) => true,
-            (Val::Int(n), Type::Uint) => n.sign() != Sign::Minus,
-            (Val::Array(xs), Type::List(t)) => xs.iter().all(|x| self.has_type(x, t)),
-            (Val::Array(xs), Type::Vector(t,n)) => xs.len() == *n && xs.iter().all(|x| self.has_type(x, t)),
-            (Val::Array(xs), Type::Tuple(ts)) => xs.len() == ts.len() && xs.iter().zip(ts).all(|(x,t)| self.has_type(x, t)),
-            _ => false,
+    fn call(&self, f: &str, args: &[Val], state: &mut EvalState) -> Result<Val, EvalError> {
+        enter(f, state, |state| self.call_here(f, args, state))
+    }
+
+    /// Applies a function value to each argument in turn. The name is the variable it came from,
+    /// for the call stack.
+    fn apply(&self, name: &str, mut f: Val, args: &[Val], state: &mut EvalState) -> Result<Val, EvalError> {
+        for arg in args {
+            let Closure { param, param_type, body, mut env } = match f {
+                Val::Func(c) => *c,
+                v => return Err(EvalError::NotFunction(v)),
+            };
+            // A parameter type that uses a hidden argument checked mode couldn't work out can't be
+            // evaluated, so that argument goes unchecked
+            let known = |x: &String| env.contains_key(x) || self.funcs.contains_key(x);
+            if state.checked && combine::free_names(&param_type).iter().all(known) {
+                let t = self.eval(&param_type, state, &env)?.unwrap_type()?;
+                if !self.has_type(arg, &t, state)? {
+                    return Err(EvalError::BadArgument(name.to_owned(), param, arg.clone(), t));
+                }
+            }
+            env.insert(param, arg.clone());
+            f = enter(name, state, |state| self.eval(&body, state, &env))?;
         }
+        Ok(f)
     }
-    */
 
-    fn call(&self, f: &str, args: &[Val], global_env: &mut HashMap<String, Val>) -> Result<Val, EvalError> {
+    /// The builtins that need the interpreter, and otherwise the ones in `call_prelude`
+    fn call_builtin(&self, f: &str, args: &[Val], state: &mut EvalState) -> Result<Val, EvalError> {
+        match f {
+            // [f 0, f 1, ..., f (n - 1)]
+            "tabulate" => {
+                let n = args[0].unwrap_usize()?;
+                state.spend_fuel(n)?;
+                (0..n).map(|i| self.apply("tabulate", args[1].clone(), &[Val::Int(i.into())], state))
+                    .collect::<Result<_, _>>()
+                    .map(Val::Array)
+            }
+            // Paid for up front, so that a huge range runs out of fuel rather than memory
+            "range" => {
+                let n = args[0].unwrap_usize()?;
+                state.spend_fuel(n)?;
+                Ok(Val::Array((0..n).map(|i| Val::Int(i.into())).collect()))
+            }
+            "is" => Ok(Val::from_bool(self.has_type(&args[0], &args[1].unwrap_type()?, state)?)),
+            _ => call_prelude(f, args),
+        }
+    }
+
+    /// Calls a function without keeping track of the call stack
+    fn call_here(&self, f: &str, args: &[Val], state: &mut EvalState) -> Result<Val, EvalError> {
         let func = self.lookup_fn(f)?;
-        if func.args.len() != args.len() {
-            return Err(EvalError::WrongNumberOfArgs(f.to_owned(), func.args.len(), args.len()));
+        if func.arg_names.len() != args.len() {
+            return Err(EvalError::WrongNumberOfArgs(f.to_owned(), func.arg_names.len(), args.len()));
         }
 
+        // The builtins without arguments are the types that signatures are written in terms of
+        let (return_types, mut env) = if state.checked && !(func.prelude && args.is_empty()) {
+            self.check_args(f, func, args, state)?
+        } else {
+            (vec![], HashMap::new())
+        };
         let result = if func.prelude {
-            match f {
-                "true" => Val::Type(Type::True),
-                "false" => Val::Type(Type::False),
-                "bool" => Val::Type(Type::Bool),
-                "int" => Val::Type(Type::Int),
-                "uint" => Val::Type(Type::Uint),
-                "string" => Val::Type(Type::String),
-                "type" => Val::Type(Type::Type),
-                "list" => Val::Type(Type::List(Box::new(args[0].unwrap_type()?))),
-                "vector" => Val::Type(Type::Vector(
-                        Box::new(args[0].unwrap_type()?),
-                        args[1].unwrap_usize()?
-                )),
-                "tuple" => Val::Type(Type::Tuple(args[0].unwrap_array_of_types()?)),
-                _ => return Err(EvalError::NoSuchPreludeFunction(f.to_owned())),
-            }
+            self.call_builtin(f, args, state)?
         } else {
-            let mut env = HashMap::new();
-            for ((name, _), value) in func.args.iter().zip(args) {
+            for (name, value) in func.arg_names.iter().zip(args) {
                 env.insert(name.clone(), value.clone());
             }
-
-            self.eval(&func.body, global_env, &env)?
+            self.eval(&func.body, state, &env)?
         };
+        for t in return_types {
+            if !self.has_type(&result, &t, state)? {
+                return Err(EvalError::BadReturnValue(f.to_owned(), result, t));
+            }
+        }
 
         Ok(result)
     }
 
-    fn eval(&self, expr: &Expr, global_env: &mut HashMap<String, Val>, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
-        match expr {
-            Expr::Int(n) => Ok(Val::Int(n.clone())),
-            Expr::Var(x) => self.lookup_or_compute_value(x, global_env, env),
-            Expr::Call(f, args) => {
-                let arg_vals:Vec<_> = args.iter().map(|x|self.eval(x,global_env,env)).collect::<Result<_,_>>()?;
-                self.call(f, &arg_vals, global_env)
+    /// Used in checked mode. The arguments have to fit at least one of the signatures, and the
+    /// return types of those signatures are the ones the result must then have.
+    ///
+    /// Hidden arguments aren't available at runtime, so they get worked out from the arguments
+    /// where possible: see `bind_hidden`. Any other hidden argument of type `type` or `eq` is
+    /// `Type::Unknown`, and a signature with some other hidden argument that can't be worked out
+    /// is skipped. Also returns the hidden arguments of the line with the body, for the lambdas
+    /// in the body to check against.
+    fn check_args(&self, f: &str, func: &Func, args: &[Val], state: &mut EvalState) -> Result<(Vec<Type>, HashMap<String, Val>), EvalError> {
+        let mut return_types = vec![];
+        let mut body_hidden = None;
+        let mut first_mismatch = None;
+        'sigs: for sig in &func.sigs {
+            let mut env = HashMap::new();
+            for ((_, typ), value) in sig.args.iter().zip(args) {
+                bind_hidden(typ, value, &sig.hidden, &mut env);
+            }
+            for (h, t) in &sig.hidden {
+                if !env.contains_key(h) {
+                    match &t.kind {
+                        ExprKind::Var(k) if k == "type" || k == "eq" => env.insert(h.clone(), Val::Type(Type::Unknown)),
+                        _ => continue 'sigs,
+                    };
+                }
+            }
+            let hidden = env.clone();
+            // Types may depend on earlier arguments, which are named as in this signature
+            let mut fits = true;
+            for ((name, typ), value) in sig.args.iter().zip(args) {
+                let t = self.eval(typ, state, &env)?.unwrap_type()?;
+                if !self.has_type(value, &t, state)? {
+                    if first_mismatch.is_none() {
+                        first_mismatch = Some(EvalError::BadArgument(f.to_owned(), name.clone(), value.clone(), t));
+                    }
+                    fits = false;
+                    break;
+                }
+                env.insert(name.clone(), value.clone());
+            }
+            if fits {
+                return_types.push(self.eval(&sig.ret, state, &env)?.unwrap_type()?);
+                if body_hidden.is_none() && sig.hidden.iter().map(|h| &h.0).eq(&func.hidden_names) {
+                    body_hidden = Some(hidden);
+                }
+            }
+        }
+        match first_mismatch {
+            Some(e) if return_types.is_empty() => Err(e),
+            _ => Ok((return_types, body_hidden.unwrap_or_default())),
+        }
+    }
+
+    fn eval(&self, expr: &Expr, state: &mut EvalState, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
+        self.eval_here(expr, state, env).map_err(|e| e.at(expr.span))
+    }
+
+    /// Evaluates an expression without attaching its span to any errors
+    fn eval_here(&self, expr: &Expr, state: &mut EvalState, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
+        state.spend_fuel(1)?;
+        if state.stack_limit.is_some_and(|limit| stack_position() < limit) {
+            return Err(EvalError::StackDepthExceeded(state.call_stack.clone()));
+        }
+        match &expr.kind {
+            ExprKind::Int(n) => Ok(Val::Int(n.clone())),
+            ExprKind::Str(s) => Ok(Val::String(s.clone())),
+            ExprKind::Var(x) => self.lookup_or_compute_value(x, state, env),
+            // Hidden arguments only matter to the type checker
+            ExprKind::Call(f, _, args) => {
+                let arg_vals:Vec<_> = args.iter().map(|x|self.eval(x,state,env)).collect::<Result<_,_>>()?;
+                // Local variables hide functions of the same name
+                match (env.get(f), self.funcs.get(f)) {
+                    (Some(v), _) => self.apply(f, v.clone(), &arg_vals, state),
+                    // The extra arguments go to the function it returns
+                    (None, Some(func)) if arg_vals.len() > func.arg_names.len() => {
+                        let (now, later) = arg_vals.split_at(func.arg_names.len());
+                        let g = self.call(f, now, state)?;
+                        self.apply(f, g, later, state)
+                    }
+                    (None, Some(func)) if arg_vals.len() < func.arg_names.len() => {
+                        self.apply(f, self.func_value(f)?, &arg_vals, state)
+                    }
+                    (None, _) => self.call(f, &arg_vals, state),
+                }
+            }
+            ExprKind::Apply(g, args) => {
+                let g = self.eval(g, state, env)?;
+                let arg_vals: Vec<_> = args.iter().map(|x|self.eval(x,state,env)).collect::<Result<_,_>>()?;
+                self.apply("a function value", g, &arg_vals, state)
+            }
+            ExprKind::Array(xs) => {
+                Ok(Val::Array(xs.iter().map(|x|self.eval(x,state,env)).collect::<Result<_,_>>()?))
+            }
+            ExprKind::If(c, a, b) => {
+                // Only the chosen branch gets evaluated
+                if self.eval(c, state, env)?.unwrap_bool()? {
+                    self.eval(a, state, env)
+                } else {
+                    self.eval(b, state, env)
+                }
+            }
+            // The annotation only matters to the type checker
+            ExprKind::Let(x, _, v, b) => {
+                let value = self.eval(v, state, env)?;
+                let mut env = env.clone();
+                env.insert(x.clone(), value);
+                self.eval(b, state, &env)
+            }
+            ExprKind::Lambda(x, t, b) => Ok(Val::Func(Box::new(Closure {
+                param: x.clone(),
+                param_type: (**t).clone(),
+                body: (**b).clone(),
+                env: env.clone(),
+            }))),
+            // The parameter type isn't needed until the type gets printed
+            ExprKind::Pi(x, a, b) => Ok(Val::Type(Type::Pi(Box::new(Closure {
+                param: x.clone(),
+                param_type: (**a).clone(),
+                body: (**b).clone(),
+                env: env.clone(),
+            })))),
+            // The types of the fields get evaluated when something gets checked against them, see
+            // `has_type`. Until then they only need the values of the local variables they use.
+            ExprKind::RecordType(fs) => Ok(Val::Type(Type::Record(Box::new(Fields {
+                types: fs.clone(),
+                env: env.iter().filter(|(x, _)| expr.mentions(x)).map(|(x, v)| (x.clone(), v.clone())).collect(),
+            })))),
+            ExprKind::SumType(ts) => Ok(Val::Type(Type::Sum(
+                ts.iter().map(|(a, t)| Ok((a.clone(), self.eval(t, state, env)?.unwrap_type()?))).collect::<Result<_, EvalError>>()?
+            ))),
+            ExprKind::Record(fs) => Ok(Val::Record(
+                fs.iter().map(|(x, v)| Ok((x.clone(), self.eval(v, state, env)?))).collect::<Result<_, EvalError>>()?
+            )),
+            ExprKind::Tag(a, x) => Ok(Val::Tag(a.clone(), Box::new(self.eval(x, state, env)?))),
+            ExprKind::Field(r, x) => match self.eval(r, state, env)? {
+                Val::Record(mut vs) => vs.remove(x).ok_or_else(|| EvalError::NoSuchField(x.clone(), Val::Record(vs))),
+                v => Err(EvalError::NotRecord(v)),
+            },
+            ExprKind::Match(x, arms) => match self.eval(x, state, env)? {
+                Val::Tag(a, v) => match arms.iter().find(|arm| arm.0 == a) {
+                    Some((_, y, b)) => {
+                        let mut env = env.clone();
+                        env.insert(y.clone(), *v);
+                        self.eval(b, state, &env)
+                    }
+                    None => Err(EvalError::UnmatchedTag(a)),
+                },
+                v => Err(EvalError::NotTag(v)),
+            },
+            // Rust's own && and || short-circuit, so the right operand is only evaluated if needed
+            ExprKind::And(a, b) => Ok(Val::from_bool(
+                self.eval(a, state, env)?.unwrap_bool()? && self.eval(b, state, env)?.unwrap_bool()?
+            )),
+            ExprKind::Or(a, b) => Ok(Val::from_bool(
+                self.eval(a, state, env)?.unwrap_bool()? || self.eval(b, state, env)?.unwrap_bool()?
+            )),
+        }
+    }
+
+    /// The arguments must already have been decoded, see `args::decode_args`
+    pub fn eval_main(&self, args: &[Val], state: &mut EvalState) -> Result<Val, EvalError> {
+        self.call("main", args, state)
+    }
+
+    /// Evaluates an expression that doesn't refer to any local variables
+    pub fn eval_closed(&self, expr: &Expr, state: &mut EvalState) -> Result<Val, EvalError> {
+        self.eval(expr, state, &HashMap::new())
+    }
+
+    /// Evaluates an expression whose only local variables are the ones given
+    pub fn eval_with_env(&self, expr: &Expr, state: &mut EvalState, env: &HashMap<String, Val>) -> Result<Val, EvalError> {
+        self.eval(expr, state, env)
+    }
+}
+
+/// Evaluates a builtin function from the prelude. The number of arguments must already have been checked.
+fn call_prelude(f: &str, args: &[Val]) -> Result<Val, EvalError> {
+    let result = match f {
+        "true" => Val::Type(Type::True),
+        "false" => Val::Type(Type::False),
+        "bool" => Val::Type(Type::Bool),
+        "int" => Val::Type(Type::Int),
+        "uint" => Val::Type(Type::Uint),
+        "string" => Val::Type(Type::String),
+        "type" => Val::Type(Type::Type),
+        "eq" => Val::Type(Type::Eq),
+        "list" => Val::Type(Type::List(Box::new(args[0].unwrap_type()?))),
+        "vector" => Val::Type(Type::Vector(
+                Box::new(args[0].unwrap_type()?),
+                args[1].unwrap_usize()?
+        )),
+        "tuple" => Val::Type(Type::Tuple(args[0].unwrap_array_of_types()?)),
+        "add" => Val::Int(args[0].unwrap_int()? + args[1].unwrap_int()?),
+        "sub" => Val::Int(args[0].unwrap_int()? - args[1].unwrap_int()?),
+        "mul" => Val::Int(args[0].unwrap_int()? * args[1].unwrap_int()?),
+        // div and mod round towards zero
+        "div" => Val::Int(args[0].unwrap_int()? / args[1].unwrap_nonzero_int()?),
+        "mod" => Val::Int(args[0].unwrap_int()? % args[1].unwrap_nonzero_int()?),
+        "neg" => Val::Int(-args[0].unwrap_int()?.clone()),
+        "lt" => Val::from_bool(args[0].unwrap_int()? < args[1].unwrap_int()?),
+        "le" => Val::from_bool(args[0].unwrap_int()? <= args[1].unwrap_int()?),
+        "gt" => Val::from_bool(args[0].unwrap_int()? > args[1].unwrap_int()?),
+        "ge" => Val::from_bool(args[0].unwrap_int()? >= args[1].unwrap_int()?),
+        "equals" => Val::from_bool(args[0] == args[1]),
+        // Strings are indexed by character, not by byte
+        "concat" => match &args[0] {
+            Val::String(a) => Val::String(format!("{}{}", a, args[1].unwrap_string()?)),
+            xs => {
+                let mut xs = xs.unwrap_array()?;
+                xs.extend(args[1].unwrap_array()?);
+                Val::Array(xs)
+            }
+        },
+        "length" => match &args[0] {
+            Val::String(s) => Val::Int(s.chars().count().into()),
+            xs => Val::Int(xs.unwrap_array()?.len().into()),
+        },
+        "substring" => {
+            let chars: Vec<_> = args[0].unwrap_string()?.chars().collect();
+            let (start, end) = (args[1].unwrap_usize()?, args[2].unwrap_usize()?);
+            if end > chars.len() {
+                return Err(EvalError::IndexOutOfBounds(end, chars.len()));
+            }
+            if start > end {
+                return Err(EvalError::IndexOutOfBounds(start, end));
+            }
+            Val::String(chars[start..end].iter().collect())
+        }
+        "char_at" => {
+            let s = args[0].unwrap_string()?;
+            let i = args[1].unwrap_usize()?;
+            match s.chars().nth(i) {
+                Some(c) => Val::String(c.to_string()),
+                None => return Err(EvalError::IndexOutOfBounds(i, s.chars().count())),
+            }
+        }
+        "parse_int" => {
+            let s = args[0].unwrap_string()?;
+            Val::Int(s.parse().map_err(|_| EvalError::InvalidInt(s.to_owned()))?)
+        }
+        "to_string" => Val::String(args[0].unwrap_int()?.to_string()),
+        "index" => {
+            let xs = args[0].unwrap_array()?;
+            let i = args[1].unwrap_usize()?;
+            match xs.get(i) {
+                Some(x) => x.clone(),
+                None => return Err(EvalError::IndexOutOfBounds(i, xs.len())),
+            }
+        }
+        "push" => {
+            let mut xs = args[0].unwrap_array()?;
+            xs.push(args[1].clone());
+            Val::Array(xs)
+        }
+        "slice" => {
+            let xs = args[0].unwrap_array()?;
+            let (start, end) = (args[1].unwrap_usize()?, args[2].unwrap_usize()?);
+            if end > xs.len() {
+                return Err(EvalError::IndexOutOfBounds(end, xs.len()));
+            }
+            if start > end {
+                return Err(EvalError::IndexOutOfBounds(start, end));
+            }
+            Val::Array(xs[start..end].to_vec())
+        }
+        _ => return Err(EvalError::NoSuchPreludeFunction(f.to_owned())),
+    };
+    Ok(result)
+}
+
+/// Works out hidden arguments from an argument's value, for checked mode. Only lengths can be read
+/// off a value exactly, so a hidden argument gets a value when it's the length in a parameter
+/// type like `vector t n`, and not otherwise.
+fn bind_hidden(param: &Expr, value: &Val, hidden: &[(String, Expr)], env: &mut HashMap<String, Val>) {
+    let xs = match value {
+        Val::Array(xs) => xs,
+        _ => return,
+    };
+    match &param.kind {
+        ExprKind::Call(f, _, ps) if f == "vector" && ps.len() == 2 => {
+            if let ExprKind::Var(h) = &ps[1].kind {
+                if hidden.iter().any(|p| &p.0 == h) && !env.contains_key(h) {
+                    env.insert(h.clone(), Val::Int(xs.len().into()));
+                }
+            }
+            for x in xs {
+                bind_hidden(&ps[0], x, hidden, env);
             }
-            Expr::Array(xs) => {
-                Ok(Val::Array(xs.iter().map(|x|self.eval(x,global_env,env)).collect::<Result<_,_>>()?))
+        }
+        ExprKind::Call(f, _, ps) if f == "list" && ps.len() == 1 => {
+            for x in xs {
+                bind_hidden(&ps[0], x, hidden, env);
             }
         }
+        ExprKind::Call(f, _, ps) if f == "tuple" && ps.len() == 1 => {
+            if let ExprKind::Array(ts) = &ps[0].kind {
+                for (t, x) in ts.iter().zip(xs) {
+                    bind_hidden(t, x, hidden, env);
+                }
+            }
+        }
+        _ => {}
     }
+}
 
-    pub fn eval_main(&self, args: &[String]) -> Result<Val, EvalError> {
-        let args_val = Val::Array(args.iter().map(|s|Val::String(s.clone())).collect());
-        let mut global_env = HashMap::new();
-        self.call("main", &[args_val], &mut global_env)
+/// Runs a call with its name on the call stack, provided that doesn't make the stack too deep
+fn enter(name: &str, state: &mut EvalState, call: impl FnOnce(&mut EvalState) -> Result<Val, EvalError>) -> Result<Val, EvalError> {
+    if let Some(max_depth) = state.max_depth {
+        if state.call_stack.len() >= max_depth {
+            return Err(EvalError::StackDepthExceeded(state.call_stack.clone()));
+        }
+    }
+    state.call_stack.push(name.to_owned());
+    let result = call(state);
+    // Popped even on failure, since the REPL carries on using the same state
+    state.call_stack.pop();
+    result
+}
+
+/// Shows where evaluation had got to, innermost call first
+struct CallStack<'a>(&'a [String]);
+
+impl fmt::Display for CallStack<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Deep recursion would otherwise print the same name thousands of times
+        const SHOWN: usize = 10;
+        for (i, name) in self.0.iter().rev().take(SHOWN).enumerate() {
+            write!(f, "{}{}", if i == 0 { " in " } else { ", called from " }, name)?;
+        }
+        if self.0.len() > SHOWN {
+            write!(f, " and {} more", self.0.len() - SHOWN)?;
+        }
+        Ok(())
+    }
+}
+
+impl EvalError {
+    /// Attaches a location, unless the error already has a more precise one
+    fn at(self, span: Option<Span>) -> Self {
+        match (self, span) {
+            (e @ EvalError::At(..), _) | (e, None) => e,
+            (e, Some(span)) => EvalError::At(span, Box::new(e)),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvalError::At(span, _) => Some(*span),
+            _ => None,
+        }
     }
 }
 
 impl fmt::Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Eval error {:?}", self)
+        match self {
+            EvalError::WrongNumberOfArgs(name, expected, got) => write!(f, "{} takes {} arguments but was given {}", name, expected, got),
+            EvalError::NoSuchFunc(name) => write!(f, "no such function {}", name),
+            EvalError::NoSuchPreludeFunction(name) => write!(f, "{} is declared in the prelude but has no builtin implementation", name),
+            EvalError::Overflow => write!(f, "number too large"),
+            EvalError::NotInteger(v) => write!(f, "expected an integer, got {}", v),
+            EvalError::NotType(v) => write!(f, "expected a type, got {}", v),
+            EvalError::NotArray(v) => write!(f, "expected an array, got {}", v),
+            EvalError::NotBool(v) => write!(f, "expected true or false, got {}", v),
+            EvalError::NotFunction(v) => write!(f, "expected a function, got {}", v),
+            EvalError::NotRecord(v) => write!(f, "expected a record, got {}", v),
+            EvalError::NoSuchField(x, v) => write!(f, "{} has no field {}", v, x),
+            EvalError::NotTag(v) => write!(f, "expected a tagged value, got {}", v),
+            EvalError::UnmatchedTag(a) => write!(f, "no arm of the match is for #{}", a),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+            EvalError::NotString(v) => write!(f, "expected a string, got {}", v),
+            EvalError::IndexOutOfBounds(i, len) => write!(f, "index {} is out of bounds for length {}", i, len),
+            EvalError::InvalidInt(s) => write!(f, "cannot parse {} as an integer", Val::String(s.clone())),
+            EvalError::OutOfFuel(stack) => write!(f, "ran out of fuel{}", CallStack(stack)),
+            EvalError::StackDepthExceeded(stack) => write!(f, "calls nested more than {} deep{}", stack.len(), CallStack(stack)),
+            EvalError::BadArgument(func, name, v, t) => write!(f, "argument {} of {} should have type {}, but got {}", name, func, t, v),
+            EvalError::BadReturnValue(func, v, t) => write!(f, "{} should return a value of type {}, but returned {}", func, t, v),
+            EvalError::At(_, e) => e.fmt(f),
+        }
     }
 }
 
 impl std::error::Error for EvalError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine, parse};
+
+    /// Evaluates `x` from the script
+    fn run(script: &str) -> Result<Val, EvalError> {
+        run_with(script, &mut EvalState::new(None))
+    }
+
+    fn run_with(script: &str, state: &mut EvalState) -> Result<Val, EvalError> {
+        let prelude = include_str!("prelude.jd");
+        let script = parse::parse(script, prelude.len() + 1).unwrap();
+        let program = combine::combine(&parse::parse(prelude, 0).unwrap(), &script).unwrap();
+        program.call("x", &[], state).map_err(innermost)
+    }
+
+    /// Evaluates an expression that can use the prelude and the given declarations
+    fn eval_in(script: &str, text: &str, fuel: Option<u64>) -> Result<Val, EvalError> {
+        let prelude = include_str!("prelude.jd");
+        let script = parse::parse(script, prelude.len() + 1).unwrap();
+        let program = combine::combine(&parse::parse(prelude, 0).unwrap(), &script).unwrap();
+        program.eval_closed(&parse::parse_expr(text, 0).unwrap(), &mut EvalState::new(fuel))
+    }
+
+    /// Like `eval_in` in checked mode, without type checking the script first
+    fn eval_checked(script: &str, text: &str) -> Result<Val, EvalError> {
+        let prelude = include_str!("prelude.jd");
+        let script = parse::parse(script, prelude.len() + 1).unwrap();
+        let program = combine::combine(&parse::parse(prelude, 0).unwrap(), &script).unwrap();
+        let mut state = EvalState::new(None);
+        state.checked = true;
+        program.eval_closed(&parse::parse_expr(text, 0).unwrap(), &mut state).map_err(innermost)
+    }
+
+    fn eval(text: &str) -> Result<Val, EvalError> {
+        eval_in("unused : int = 0;", text, None)
+    }
+
+    /// Errors come wrapped in the location they happened at
+    fn innermost(e: EvalError) -> EvalError {
+        match e {
+            EvalError::At(_, e) => innermost(*e),
+            e => e,
+        }
+    }
+
+    fn run_int(script: &str) -> BigInt {
+        match run(script).unwrap() {
+            Val::Int(n) => n,
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn if_only_evaluates_the_branch_it_takes() {
+        assert!(matches!(run("x : uint = if true then 1 else if 1 then 2 else 3;"), Ok(Val::Int(n)) if n == 1.into()));
+        assert!(matches!(run("x : uint = if false then if 1 then 2 else 3 else 4;"), Ok(Val::Int(n)) if n == 4.into()));
+        assert!(matches!(run("x : uint = if false then 1 else if 1 then 2 else 3;"), Err(EvalError::NotBool(_))));
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        assert!(matches!(run("x : bool = false && (if 1 then true else false);"), Ok(Val::Type(Type::False))));
+        assert!(matches!(run("x : bool = true || (if 1 then true else false);"), Ok(Val::Type(Type::True))));
+        assert!(matches!(run("x : bool = true && (if 1 then true else false);"), Err(EvalError::NotBool(_))));
+        assert!(matches!(run("x : bool = false || true;"), Ok(Val::Type(Type::True))));
+    }
+
+    #[test]
+    fn arithmetic_doesnt_overflow_and_rounds_towards_zero() {
+        assert_eq!(run_int("x : int = 1000000000000 * 1000000000000;"), "1000000000000000000000000".parse().unwrap());
+        assert_eq!(run_int("x : int = -7 / 2;"), BigInt::from(-3));
+        assert_eq!(run_int("x : int = -7 % 2;"), BigInt::from(-1));
+        assert!(matches!(run("x : int = 1 / 0;"), Err(EvalError::DivisionByZero)));
+        assert!(matches!(run("x : int = 1 % (2 - 2);"), Err(EvalError::DivisionByZero)));
+        assert!(matches!(run("x : bool = 1 < 2 && 2 <= 2;"), Ok(Val::Type(Type::True))));
+    }
+
+    #[test]
+    fn array_builtins_check_bounds() {
+        assert_eq!(eval("index (push [1 2] 3) 2").unwrap(), eval("3").unwrap());
+        assert_eq!(eval("slice [1 2 3] 1 3").unwrap(), eval("[2 3]").unwrap());
+        assert_eq!(eval("slice [1 2 3] 3 3").unwrap(), eval("[]").unwrap());
+        assert!(matches!(eval("index [1 2] 2").map_err(innermost), Err(EvalError::IndexOutOfBounds(2, 2))));
+        assert!(matches!(eval("index [] 0").map_err(innermost), Err(EvalError::IndexOutOfBounds(0, 0))));
+        assert!(matches!(eval("slice [1 2 3] 2 1").map_err(innermost), Err(EvalError::IndexOutOfBounds(2, 1))));
+    }
+
+    #[test]
+    fn errors_point_at_the_innermost_expression() {
+        assert_eq!(eval("1 + (1 / 0)").unwrap_err().span(), Some(Span { start: 4, end: 11 }));
+        assert_eq!(eval("[1 (index [] 0)]").unwrap_err().span(), Some(Span { start: 3, end: 15 }));
+    }
+
+    #[test]
+    fn hidden_args_are_ignored() {
+        let script = "first {t:type} {n:uint} (xs:vector t n) : t = index xs 0;";
+        assert_eq!(eval_in(script, "first {t=int} {n=2} [5 6]", None).unwrap(), eval("5").unwrap());
+    }
+
+    #[test]
+    fn mutually_recursive_functions_call_each_other() {
+        let script = "even (n:int) : bool = if n <= 0 then true else odd (n - 1);\nodd (n:int) : bool = if n <= 0 then false else even (n - 1);";
+        assert!(matches!(run(&format!("{}\nx : bool = even 10;", script)), Ok(Val::Type(Type::True))));
+        assert!(matches!(run(&format!("{}\nx : bool = odd 10;", script)), Ok(Val::Type(Type::False))));
+        assert_eq!(run_int("fact (n:int) : int = if n <= 0 then 1 else n * fact (n - 1);\nx : int = fact 20;"), "2432902008176640000".parse().unwrap());
+    }
+
+    #[test]
+    fn equality_is_structural() {
+        assert!(matches!(run("x : bool = [1 [2 3]] == [1 [2 3]];"), Ok(Val::Type(Type::True))));
+        assert!(matches!(run("x : bool = [1 2] == [1 2 3];"), Ok(Val::Type(Type::False))));
+        assert!(matches!(run("x : bool = 2 * 3 == 6 && -1 == 0 - 1;"), Ok(Val::Type(Type::True))));
+        assert!(matches!(run("x : bool = (1 == 2) == false;"), Ok(Val::Type(Type::True))));
+    }
+
+    #[test]
+    fn string_builtins_count_characters() {
+        let string = |s: &str| Val::String(s.to_owned());
+        assert_eq!(run_int("x : uint = length \"héllo\";"), BigInt::from(5));
+        assert_eq!(run("x : string = substring \"héllo\" 1 3;").unwrap(), string("él"));
+        assert_eq!(run("x : string = char_at \"héllo\" 1;").unwrap(), string("é"));
+        assert_eq!(run("x : string = concat \"a\" (to_string (-12));").unwrap(), string("a-12"));
+        assert_eq!(run_int("x : int = parse_int \"-12\" + 1;"), BigInt::from(-11));
+        assert!(matches!(run("x : int = parse_int \"x\";"), Err(EvalError::InvalidInt(_))));
+        assert!(matches!(run("x : string = substring \"abc\" 2 4;"), Err(EvalError::IndexOutOfBounds(4, 3))));
+        assert!(matches!(run("x : string = char_at \"abc\" 3;"), Err(EvalError::IndexOutOfBounds(3, 3))));
+    }
+
+    #[test]
+    fn limits_report_the_call_stack() {
+        let script = "loop (n:int) : int = helper n;\nhelper (n:int) : int = loop (n + 1);\nx : int = loop 0;";
+        match run_with(script, &mut EvalState::new(Some(100))) {
+            Err(EvalError::OutOfFuel(stack)) => {
+                assert!(stack.len() > 3 && stack[..3] == ["x", "loop", "helper"], "{:?}", stack);
+            }
+            r => panic!("{:?}", r),
+        }
+        let mut state = EvalState::new(None);
+        state.max_depth = Some(5);
+        let e = run_with(script, &mut state).unwrap_err();
+        assert_eq!(e.to_string().lines().next().unwrap(), "calls nested more than 5 deep in helper, called from loop, called from helper, called from loop, called from x");
+        assert!(state.call_stack.is_empty());
+    }
+
+    #[test]
+    fn local_definitions_dont_leak_into_globals() {
+        let script = "g : int = 5;\nf (g:int) : int = y * 2 where g = 1, y = g + 1;";
+        let run_in = |body: &str| run_int(&format!("{}\nx : int = {};", script, body));
+        assert_eq!(run_in("(let g = 1 in g) + g"), BigInt::from(6));
+        assert_eq!(run_in("g + (let g = 1 in g)"), BigInt::from(6));
+        assert_eq!(run_in("f 10 + g"), BigInt::from(9));
+        assert_eq!(run_in("let g = g + 1 in let g = g * 2 in g"), BigInt::from(12));
+    }
+
+    #[test]
+    fn functions_can_be_used_as_values() {
+        let script = "add3 (x:int) (y:int) (z:int) : int = x + y + z;\ntwice (f:int -> int) (x:int) : int = f (f x);";
+        assert_eq!(eval_in(script, "twice (add3 1 2) 0", None).unwrap(), eval("6").unwrap());
+        assert_eq!(eval_in(script, "(add3 1) 2 3", None).unwrap(), eval("6").unwrap());
+        assert_eq!(eval_in(script, "let f = add3 in f 1 2 3", None).unwrap(), eval("6").unwrap());
+        assert_eq!(eval_in(script, "{f = add3 1 2}.f 4", None).unwrap(), eval("7").unwrap());
+        assert_eq!(eval("(\\(x:int) -> \\(y:int) -> x - y) 3 1").unwrap(), eval("2").unwrap());
+    }
+
+    #[test]
+    fn match_takes_the_arm_for_the_tag() {
+        let script = "shape : type = {circle:int | square:int | empty};\narea (s:shape) : int = match s {#circle r -> 3 * r * r, #square w -> w * w, #empty -> 0};";
+        assert_eq!(eval_in(script, "area (#square 3)", None).unwrap(), eval("9").unwrap());
+        assert_eq!(eval_in(script, "area (#circle 2)", None).unwrap(), eval("12").unwrap());
+        assert_eq!(eval_in(script, "area #empty", None).unwrap(), eval("0").unwrap());
+        assert_eq!(eval("match #a 1 {#a x -> let x = x + 1 in x, #b x -> x}").unwrap(), eval("2").unwrap());
+    }
+
+    #[test]
+    fn fields_are_projected_from_records() {
+        assert_eq!(eval("{x = 1, y = [2 3]}.y").unwrap(), eval("[2 3]").unwrap());
+        assert_eq!(eval("{a = {b = 4}}.a.b").unwrap(), eval("4").unwrap());
+        assert_eq!(eval("let r = {len = 2, data = [1 2]} in index r.data (r.len - 1)").unwrap(), eval("2").unwrap());
+    }
+
+    #[test]
+    fn captured_variables_keep_their_meaning() {
+        let script = "two (x:uint) : uint = 2;";
+        let pair = "let f = \\(x:uint) -> vector int (two x) in {two:int, data:f 0}";
+        assert_eq!(eval_in(script, &format!("is {{two = 5, data = [1 2]}} ({})", pair), None).unwrap(), eval("true").unwrap());
+        assert_eq!(eval_in(script, &format!("is {{two = 1, data = [1]}} ({})", pair), None).unwrap(), eval("false").unwrap());
+        let shadowing = "let f = \\(x:uint) -> two x in \\(two:uint) -> f two";
+        assert_eq!(eval_in(script, shadowing, None).unwrap().to_string(), "\\(two':uint) -> let f = \\(x:uint) -> two x in f two'");
+        assert_eq!(eval_in(script, &format!("({}) 7", shadowing), None).unwrap(), eval("2").unwrap());
+    }
+
+    #[test]
+    fn checked_mode_works_out_lengths() {
+        let script = "same {t:type} {n:uint} (xs:vector t n) : vector t n = [1];\nboth {n:uint} (xs:vector int n) (ys:vector int n) : int = 0;";
+        assert_eq!(eval_checked(script, "same [2]").unwrap(), eval("[1]").unwrap());
+        match eval_checked(script, "same [1 2]") {
+            Err(EvalError::BadReturnValue(f, _, t)) => assert_eq!((f.as_str(), t.to_string().as_str()), ("same", "vector _ 2")),
+            r => panic!("{:?}", r),
+        }
+        assert!(eval_checked(script, "both [1] [2]").is_ok());
+        match eval_checked(script, "both [1] [2 3]") {
+            Err(EvalError::BadArgument(f, x, _, _)) => assert_eq!((f.as_str(), x.as_str()), ("both", "ys")),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn checked_mode_checks_lambdas_and_builtins() {
+        let script = "id {t:type} (x:t) : t = (\\(y:t) -> y) x;\nsized {n:uint} (xs:vector int n) : int = (\\(ys:vector int n) -> 0) [1 2];";
+        assert!(matches!(eval_checked(script, "(\\(x:uint) -> x) (-1)"), Err(EvalError::BadArgument(_, x, _, _)) if x == "x"));
+        assert!(matches!(eval_checked(script, "range (-1)"), Err(EvalError::BadArgument(f, _, _, _)) if f == "range"));
+        assert_eq!(eval_checked(script, "id 3").unwrap(), eval("3").unwrap());
+        assert_eq!(eval_checked(script, "sized [1 2]").unwrap(), eval("0").unwrap());
+        assert!(matches!(eval_checked(script, "sized [1]"), Err(EvalError::BadArgument(_, ys, _, _)) if ys == "ys"));
+    }
+
+    #[test]
+    fn builtins_that_build_arrays_pay_for_each_element() {
+        assert!(matches!(eval_in("unused : int = 0;", "range 100000000000", Some(100)).map_err(innermost), Err(EvalError::OutOfFuel(_))));
+        assert!(matches!(eval_in("unused : int = 0;", "tabulate 100000000000 (\\(i:uint) -> i)", Some(100)).map_err(innermost), Err(EvalError::OutOfFuel(_))));
+        assert_eq!(eval_in("unused : int = 0;", "range 3", Some(10)).unwrap(), eval("[0 1 2]").unwrap());
+    }
+}
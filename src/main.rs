@@ -1,35 +1,370 @@
+mod args;
 mod ast;
 mod combine;
 mod eval;
 mod parse;
+mod repl;
+mod source;
 mod typecheck;
 
-use clap::{App, AppSettings, Arg};
-use std::fs;
+use ast::{Script, Span};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use source::Sources;
+use std::{fmt, fs, io, process, thread};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Debug)]
+enum Error {
+    Io(String, io::Error),
+    Parse(parse::ParseErr),
+    Combine(combine::CombineError),
+    Type(Box<typecheck::TypeError>),
+    Eval(eval::EvalError),
+    Args(args::ArgError),
+    Repl(String),
+}
+
+/// Used unless `--max-depth` says otherwise
+const DEFAULT_MAX_DEPTH: usize = 10_000;
+/// The largest `--max-depth` accepted. Its stack takes about 3.2G of address space, which many
+/// systems won't hand out to one thread even though little of it gets used.
+const MAX_MAX_DEPTH: usize = 50_000;
+/// Native stack needed for each nested call. A call to something like `fact` takes about 52K in
+/// an unoptimized build, plus about 17K for each extra level of nesting in the body's expression.
+/// An optimized build needs much less.
+const STACK_PER_CALL: usize = 64 * 1024;
+/// Native stack for parsing, combining and type checking, which happen before anything gets called
+const BASE_STACK: usize = 16 * 1024 * 1024;
+
+fn main() {
+    let number = |s: String| s.parse::<u64>().map(|_| ()).map_err(|e| e.to_string());
+    let depth = |s: String| s.parse::<usize>().map_err(|e| e.to_string()).and_then(check_max_depth);
     let matches = App::new("justdep")
-        .settings(&[AppSettings::TrailingVarArg])
-        .arg(Arg::with_name("SCRIPT").required(true).help("Input script"))
-        .arg(Arg::with_name("ARGS").multiple(true).help("Args to run script with"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("fuel").long("fuel").takes_value(true).global(true).validator(number)
+            .help("Maximum number of expressions to evaluate"))
+        .arg(Arg::with_name("max-depth").long("max-depth").takes_value(true).global(true).validator(depth)
+            .help("Maximum depth of nested function calls, up to 50000 [default: 10000]"))
+        .subcommand(SubCommand::with_name("check")
+            .about("Type check scripts without running them")
+            .arg(Arg::with_name("SCRIPTS").required(true).multiple(true).help("Scripts to check")))
+        .subcommand(SubCommand::with_name("run")
+            .about("Run a script's main function")
+            // Everything after the script belongs to it, so `run script.jd --help` describes the script
+            .settings(&[AppSettings::TrailingVarArg, AppSettings::AllowLeadingHyphen, AppSettings::DisableHelpFlags, AppSettings::DisableVersion])
+            .arg(Arg::with_name("checked").long("checked").help("Check the arguments and results of calls, and the arguments of lambdas, against their declared types. Of the hidden arguments, only vector lengths are known."))
+            .arg(Arg::with_name("SCRIPT").required(true).help("Input script"))
+            .arg(Arg::with_name("ARGS").multiple(true).allow_hyphen_values(true).help("Args to run script with, or --help to list them")))
+        .subcommand(SubCommand::with_name("eval")
+            .about("Evaluate an expression")
+            .arg(Arg::with_name("EXPR").required(true).help("Expression to evaluate"))
+            .arg(Arg::with_name("SCRIPT").help("Script whose declarations the expression can use")))
+        .subcommand(SubCommand::with_name("repl").about("Declare functions and evaluate expressions interactively"))
         .get_matches();
 
-    let args:Vec<_> = if let Some(vs) = matches.values_of("ARGS") {
-        vs.map(|s|s.to_owned()).collect()
-    } else {
-        vec![]
-    };
+    let (command, m) = matches.subcommand();
+    let m = m.expect("clap requires a subcommand");
+    let fuel = m.value_of("fuel").map(|s| s.parse().unwrap());
+    let max_depth = m.value_of("max-depth").map_or(DEFAULT_MAX_DEPTH, |s| s.parse().unwrap());
+    let mut state = eval::EvalState::new(fuel);
+    state.max_depth = Some(max_depth);
+
+    let command = command.to_owned();
+    let m = m.clone();
+    let code = with_stack(state, move |state| dispatch(&command, &m, state))
+        .map(|result| result.unwrap_or(1))
+        .unwrap_or_else(|e| {
+            eprintln!("error: cannot reserve {}M of stack for calls nested {} deep: {}; try a smaller --max-depth",
+                stack_size(max_depth) >> 20, max_depth, e);
+            1
+        });
+    process::exit(code);
+}
+
+/// Each level of nesting needs its own share of the stack, so the depth is capped at a size the
+/// interpreter can usually get
+fn check_max_depth(depth: usize) -> Result<(), String> {
+    if depth > MAX_MAX_DEPTH {
+        return Err(format!("calls can nest at most {} deep, since each level needs {}K of stack", MAX_MAX_DEPTH, STACK_PER_CALL >> 10));
+    }
+    Ok(())
+}
 
-    let input_filename = matches.value_of("SCRIPT").unwrap();
+/// The stack for calls nested `depth` deep, and for everything that happens before they start
+fn stack_size(depth: usize) -> usize {
+    depth.saturating_mul(STACK_PER_CALL).saturating_add(BASE_STACK)
+}
 
-    let prelude_script = parse::parse(include_str!("prelude.jd"))?;
+/// Evaluation recurses on the native stack, so it runs on a thread whose stack is big enough for
+/// the deepest allowed nesting of calls. Returns `Err` if the thread can't be started, and `Ok(None)`
+/// if it panics.
+fn with_stack<T: Send + 'static>(mut state: eval::EvalState, f: impl FnOnce(eval::EvalState) -> T + Send + 'static) -> io::Result<Option<T>> {
+    let size = stack_size(state.max_depth.unwrap_or(DEFAULT_MAX_DEPTH));
+    let thread = thread::Builder::new()
+        .stack_size(size)
+        .spawn(move || {
+            // Leaves half the base allowance free for printing the error
+            state.limit_stack(size - BASE_STACK / 2);
+            f(state)
+        })?;
+    Ok(thread.join().ok())
+}
 
-    let input = fs::read_to_string(input_filename)?;
-    let script = parse::parse(&input)?;
-    let program = combine::combine(&prelude_script, &script)?;
-    typecheck::type_check(&program)?;
-    let result = program.eval_main(&args)?;
+/// Runs a subcommand, returning the exit code
+fn dispatch(command: &str, m: &ArgMatches, mut state: eval::EvalState) -> i32 {
+    let mut sources = Sources::new();
+    let errors = match command {
+        "check" => check(&mut sources, m.values_of("SCRIPTS").unwrap()),
+        "run" => {
+            let args: Vec<_> = m.values_of("ARGS").map_or(vec![], |vs| vs.map(|s|s.to_owned()).collect());
+            state.checked = m.is_present("checked");
+            run(&mut sources, m.value_of("SCRIPT").unwrap(), &args, state).err().unwrap_or_default()
+        }
+        "eval" => eval(&mut sources, m.value_of("EXPR").unwrap(), m.value_of("SCRIPT"), state).err().unwrap_or_default(),
+        "repl" => repl::Repl::new(state)
+            .and_then(|mut repl| repl.run().map_err(|e| one(Error::Io("<stdin>".to_owned(), e))))
+            .err().unwrap_or_default(),
+        _ => unreachable!("clap only accepts known subcommands"),
+    };
+    for e in &errors {
+        eprintln!("{}", sources.render(e.span(), &e.to_string()));
+    }
+    // The exit code is that of the first failure
+    errors.first().map_or(0, Error::exit_code)
+}
 
-    println!("{:?}", result);
+/// Each script is checked on its own, and they all get checked even if an earlier one fails
+fn check<'a>(sources: &mut Sources, filenames: impl Iterator<Item = &'a str>) -> Vec<Error> {
+    filenames.flat_map(|filename| load(sources, Some(filename)).err().unwrap_or_default()).collect()
+}
+
+fn run(sources: &mut Sources, filename: &str, args: &[String], mut state: eval::EvalState) -> Result<(), Vec<Error>> {
+    let program = load(sources, Some(filename))?;
+    if args == ["--help"] {
+        println!("{}", args::usage(&program, filename).map_err(one)?);
+        return Ok(());
+    }
+    let args = args::decode_args(&program, args, &mut state).map_err(one)?;
+    println!("{}", program.eval_main(&args, &mut state).map_err(one)?);
+    Ok(())
+}
+
+fn eval(sources: &mut Sources, text: &str, filename: Option<&str>, mut state: eval::EvalState) -> Result<(), Vec<Error>> {
+    let program = load(sources, filename)?;
+    let expr = parse::parse_expr(text, sources.add("<expr>", text)).map_err(one)?;
+    typecheck::type_of(&program, &expr).map_err(one)?;
+    println!("{}", program.eval_closed(&expr, &mut state).map_err(one)?);
     Ok(())
 }
+
+/// Parses, combines and type checks a script along with the prelude and the standard library.
+/// With no script, the program is just those two.
+fn load(sources: &mut Sources, filename: Option<&str>) -> Result<combine::Program, Vec<Error>> {
+    let (prelude_script, mut script) = builtins(sources)?;
+    if let Some(filename) = filename {
+        let input = fs::read_to_string(filename).map_err(|e| one(Error::Io(filename.to_owned(), e)))?;
+        let user_script = parse::parse(&input, sources.add(filename, &input)).map_err(one)?;
+        combine::check_not_reserved(&script, &user_script).map_err(all)?;
+        script.decls.extend(user_script.decls);
+    }
+    let program = combine::combine(&prelude_script, &script).map_err(all)?;
+    typecheck::type_check(&program).map_err(all)?;
+    Ok(program)
+}
+
+/// The prelude, whose functions are builtins, and the standard library, which is written in the
+/// language itself and gets checked and run like the rest of the script
+fn builtins(sources: &mut Sources) -> Result<(Script, Script), Vec<Error>> {
+    let prelude = include_str!("prelude.jd");
+    let stdlib = include_str!("stdlib.jd");
+    Ok((
+        parse::parse(prelude, sources.add("<prelude>", prelude)).map_err(one)?,
+        parse::parse(stdlib, sources.add("<stdlib>", stdlib)).map_err(one)?,
+    ))
+}
+
+/// For stages that stop at the first error
+fn one<E: Into<Error>>(e: E) -> Vec<Error> {
+    vec![e.into()]
+}
+
+/// For stages that report every error they find
+fn all<E: Into<Error>>(es: Vec<E>) -> Vec<Error> {
+    es.into_iter().map(Into::into).collect()
+}
+
+impl Error {
+    /// Distinct for each stage, so that scripts can tell what went wrong
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(..) | Error::Args(_) | Error::Repl(_) => 1,
+            Error::Parse(_) => 2,
+            Error::Combine(_) => 3,
+            Error::Type(_) => 4,
+            Error::Eval(_) => 5,
+        }
+    }
+
+    fn span(&self) -> Option<Span> {
+        match self {
+            Error::Io(..) | Error::Args(_) | Error::Repl(_) => None,
+            Error::Parse(e) => Some(e.span),
+            Error::Combine(e) => e.span(),
+            Error::Type(e) => e.span(),
+            Error::Eval(e) => e.span(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(filename, e) => write!(f, "{}: {}", filename, e),
+            Error::Parse(e) => e.fmt(f),
+            Error::Combine(e) => e.fmt(f),
+            Error::Type(e) => e.fmt(f),
+            Error::Eval(e) => e.fmt(f),
+            Error::Args(e) => e.fmt(f),
+            Error::Repl(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<parse::ParseErr> for Error {
+    fn from(e: parse::ParseErr) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<combine::CombineError> for Error {
+    fn from(e: combine::CombineError) -> Self {
+        Error::Combine(e)
+    }
+}
+
+impl From<typecheck::TypeError> for Error {
+    fn from(e: typecheck::TypeError) -> Self {
+        Error::Type(Box::new(e))
+    }
+}
+
+impl From<args::ArgError> for Error {
+    fn from(e: args::ArgError) -> Self {
+        Error::Args(e)
+    }
+}
+
+impl From<eval::EvalError> for Error {
+    fn from(e: eval::EvalError) -> Self {
+        Error::Eval(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `main` from the script, along with the standard library, on a stack set up as `main` sets it up
+    fn run_main(text: &'static str, arg: i32, state: eval::EvalState) -> Result<eval::Val, eval::EvalError> {
+        with_stack(state, move |mut state| {
+            let mut sources = Sources::new();
+            let (prelude, mut script) = builtins(&mut sources).unwrap();
+            script.decls.extend(parse::parse(text, sources.add("script.jd", text)).unwrap().decls);
+            let program = combine::combine(&prelude, &script).unwrap();
+            program.eval_main(&[eval::Val::Int(arg.into())], &mut state)
+        }).unwrap().unwrap()
+    }
+
+    fn depth_exceeded(e: &eval::EvalError) -> bool {
+        match e {
+            eval::EvalError::StackDepthExceeded(_) => true,
+            eval::EvalError::At(_, e) => depth_exceeded(e),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn deep_recursion_reports_the_depth() {
+        let script = "main (n:int) : int = if n <= 0 then 1 else n * main (n - 1);";
+        let mut state = eval::EvalState::new(None);
+        state.max_depth = Some(1000);
+        match run_main(script, 5000, state) {
+            Err(e) => assert!(e.to_string().starts_with("calls nested more than 1000 deep"), "{}", e),
+            Ok(v) => panic!("got {}", v),
+        }
+        let mut state = eval::EvalState::new(None);
+        state.max_depth = Some(DEFAULT_MAX_DEPTH);
+        assert!(run_main(script, 9000, state).is_ok());
+    }
+
+    /// Writes each script to a file of its own, named after the test so that tests running at the
+    /// same time don't clash
+    fn script_files(test: &str, scripts: &[&str]) -> Vec<String> {
+        scripts.iter().enumerate().map(|(i, text)| {
+            let path = std::env::temp_dir().join(format!("just-dep-{}-{}-{}.jd", test, process::id(), i));
+            fs::write(&path, text).unwrap();
+            path.to_str().unwrap().to_owned()
+        }).collect()
+    }
+
+    #[test]
+    fn check_reports_every_script() {
+        let files = script_files("check", &[
+            "main : int = 1;",
+            "main : int = ;",
+            "main : int = f 1;",
+            "main : int = \"a\";\nother : string = 1;",
+        ]);
+        let errors = check(&mut Sources::new(), files.iter().map(String::as_str));
+        let codes: Vec<_> = errors.iter().map(Error::exit_code).collect();
+        assert_eq!(codes, [2, 3, 4, 4]);
+        assert!(check(&mut Sources::new(), files[..1].iter().map(String::as_str)).is_empty());
+        let missing = check(&mut Sources::new(), ["/nonexistent/script.jd"].iter().copied());
+        assert_eq!(missing.iter().map(Error::exit_code).collect::<Vec<_>>(), [1]);
+        for f in files {
+            fs::remove_file(f).unwrap();
+        }
+    }
+
+    #[test]
+    fn scripts_cant_reuse_stdlib_names() {
+        let mut sources = Sources::new();
+        let (_, stdlib) = builtins(&mut sources).unwrap();
+        let text = "map (x:int) : int = x;\nsquare (x:int) : int = x * x;\nfold : int = 0;";
+        let script = parse::parse(text, sources.add("script.jd", text)).unwrap();
+        let errors: Vec<_> = combine::check_not_reserved(&stdlib, &script).unwrap_err().iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors, ["map is reserved by the standard library", "fold is reserved by the standard library"]);
+    }
+
+    #[test]
+    fn stdlib_functions_run() {
+        let script = "main (n:int) : int = sum (filter (\\(x:int) -> x > 1) (reverse [n 2 1]));";
+        assert_eq!(run_main(script, 4, eval::EvalState::new(None)).unwrap().to_string(), "6");
+        let mut state = eval::EvalState::new(None);
+        state.checked = true;
+        assert_eq!(run_main(script, 4, state).unwrap().to_string(), "6");
+    }
+
+    #[test]
+    fn eval_reports_each_stage_with_its_own_exit_code() {
+        let code = |text| eval(&mut Sources::new(), text, None, eval::EvalState::new(None)).unwrap_err()[0].exit_code();
+        assert_eq!(code("1 +"), 2);
+        assert_eq!(code("1 + true"), 4);
+        assert_eq!(code("1 / 0"), 5);
+        assert!(eval(&mut Sources::new(), "1 + 1", None, eval::EvalState::new(None)).is_ok());
+    }
+
+    #[test]
+    fn max_depth_is_capped() {
+        assert!(check_max_depth(MAX_MAX_DEPTH).is_ok());
+        assert_eq!(check_max_depth(MAX_MAX_DEPTH + 1).unwrap_err(), "calls can nest at most 50000 deep, since each level needs 64K of stack");
+    }
+
+    /// Each call here uses more stack than `STACK_PER_CALL` allows for
+    #[test]
+    fn deeply_nested_bodies_stop_before_the_stack_runs_out() {
+        let script = "main (n:int) : int = if n <= 0 then 0 else 1 + (1 + (1 + (1 + (1 + (1 + main (n - 1))))));";
+        let mut state = eval::EvalState::new(None);
+        state.max_depth = Some(DEFAULT_MAX_DEPTH);
+        assert!(run_main(script, 1_000_000, state).as_ref().is_err_and(depth_exceeded));
+    }
+}
@@ -1,33 +1,76 @@
-use crate::ast::{Expr, Decl, Script};
+use crate::ast::{Expr, ExprKind, Decl, Script, Sig, Span};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
     character::complete::{digit1, multispace0},
-    combinator::{all_consuming, map, value},
-    multi::{many0, many1},
+    combinator::{all_consuming, cut, map, opt, value},
+    multi::{many0, many1, separated_list0, separated_list1},
     sequence::{delimited, preceded, terminated},
     Finish, IResult,
 };
+use std::cell::Cell;
 use std::collections::{HashSet};
 use std::{cmp::Ordering, fmt};
 
-/// Parsing entry point
-pub fn parse(input: &str) -> Result<Script, ParseErr> {
+/// Parsing entry point. `offset` is where the input starts in the source map.
+pub fn parse(input: &str, offset: usize) -> Result<Script, ParseErr> {
+    SOURCE.with(|s| s.set((offset, input.len())));
     Ok(all_consuming(preceded(whitespace, script))(input)
         .finish()
-        .map_err(|e| ParseErr::new(e, input))?
+        .map_err(|e| ParseErr::new(e, input, offset))?
         .1)
 }
 
+/// Parses a single expression, such as one typed into the REPL
+pub fn parse_expr(input: &str, offset: usize) -> Result<Expr, ParseErr> {
+    SOURCE.with(|s| s.set((offset, input.len())));
+    Ok(all_consuming(preceded(whitespace, body_expr))(input)
+        .finish()
+        .map_err(|e| ParseErr::new(e, input, offset))?
+        .1)
+}
+
+thread_local! {
+    /// The offset and length of the input currently being parsed. The parsers only ever see the
+    /// remaining input, so this is what lets them turn it back into a position.
+    static SOURCE: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+fn position(input: &str) -> usize {
+    SOURCE.with(|s| {
+        let (offset, len) = s.get();
+        offset + len - input.len()
+    })
+}
+
+/// The span of whatever was consumed between `input` and `rest`, not counting trailing whitespace
+fn span_between(input: &str, rest: &str) -> Span {
+    let start = position(input);
+    let consumed = input[..input.len() - rest.len()].trim_end();
+    Span {
+        start,
+        end: start + consumed.len(),
+    }
+}
+
+fn spanned<'a>(mut parser: impl FnMut(&'a str) -> IResult<&'a str, ExprKind, Err>) -> impl FnMut(&'a str) -> IResult<&'a str, Expr, Err> {
+    move |input| {
+        let (rest, kind) = parser(input)?;
+        Ok((rest, Expr::new(kind, span_between(input, rest))))
+    }
+}
+
 fn script(input: &str) -> IResult<&str, Script, Err> {
     map(many1(func), |decls|Script{decls})(input)
 }
 
 fn func(input: &str) -> IResult<&str, (String, Decl), Err> {
+    let start = input;
     let (input, name) = word_owned(input)?;
-    let (input, args) = many0(arg)(input)?;
-    let arg_names: HashSet<_> = args.iter().map(|a| a.0.clone()).collect();
-    if arg_names.len() < args.len() {
+    let (input, params) = many0(alt((hidden_param, map(arg, Param::Normal))))(input)?;
+    let (hidden, args) = split_params(params);
+    let arg_names: HashSet<_> = hidden.iter().chain(&args).map(|a| a.0.clone()).collect();
+    if arg_names.len() < hidden.len() + args.len() {
         return Err(nom::Err::Failure(Err {
             remaining: input.len(),
             message: "Duplicate argument name".to_owned(),
@@ -35,10 +78,44 @@ fn func(input: &str) -> IResult<&str, (String, Decl), Err> {
     }
     let (input, ()) = symbol(":")(input)?;
     let (input, ret) = expr(input)?;
-    let (input, ()) = symbol("=")(input)?;
-    let (input, body) = expr(input)?;
+    let (input, body) = opt(preceded(symbol("="), body_expr))(input)?;
     let (input, ()) = symbol(";")(input)?;
-    Ok((input, (name, Decl { args, ret, body })))
+    let span = span_between(start, input);
+    Ok((input, (name, Decl { sig: Sig { hidden, args, ret }, body, span })))
+}
+
+/// Hidden arguments get collected separately from normal ones
+enum Param<T> {
+    Hidden(String, Expr),
+    Normal(T),
+}
+
+fn split_params<T>(params: Vec<Param<T>>) -> (Vec<(String, Expr)>, Vec<T>) {
+    let mut hidden = vec![];
+    let mut normal = vec![];
+    for param in params {
+        match param {
+            Param::Hidden(name, x) => hidden.push((name, x)),
+            Param::Normal(x) => normal.push(x),
+        }
+    }
+    (hidden, normal)
+}
+
+/// `{name:type}` in a declaration, or `{name=value}` at a call site
+fn hidden<'a, T>(sep: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, Param<T>, Err> {
+    move |input| {
+        let (input, ()) = symbol("{")(input)?;
+        let (input, name) = word_owned(input)?;
+        let (input, ()) = symbol(sep)(input)?;
+        let (input, x) = expr(input)?;
+        let (input, ()) = symbol("}")(input)?;
+        Ok((input, Param::Hidden(name, x)))
+    }
+}
+
+fn hidden_param<T>(input: &str) -> IResult<&str, Param<T>, Err> {
+    hidden(":")(input)
 }
 
 fn arg(input: &str) -> IResult<&str, (String, Expr), Err> {
@@ -51,30 +128,357 @@ fn arg(input: &str) -> IResult<&str, (String, Expr), Err> {
 }
 
 fn expr(input: &str) -> IResult<&str, Expr, Err> {
-    alt((word_with_args, tight_expr))(input)
+    alt((if_expr, let_expr, match_expr, binder_expr(symbol("\\"), ExprKind::Lambda), binder_expr(keyword("pi"), ExprKind::Pi), arrow_expr))(input)
+}
+
+/// `a -> b` is the type of functions from `a` to `b`. It associates to the right, and binds more
+/// loosely than any infix operator.
+fn arrow_expr(input: &str) -> IResult<&str, Expr, Err> {
+    let start = input;
+    let (input, a) = binary_expr(0, input)?;
+    match symbol(ARROW)(input) {
+        Ok((input, ())) => {
+            let (input, b) = expr(input)?;
+            let kind = ExprKind::Pi("_".to_owned(), Box::new(a), Box::new(b));
+            Ok((input, Expr::new(kind, span_between(start, input))))
+        }
+        Err(nom::Err::Error(_)) => Ok((input, a)),
+        Err(e) => Err(e),
+    }
+}
+
+/// A lambda `\(x:t) -> body` or a function type `pi (x:a) -> b`. With several parameters, each
+/// one gets bound separately, so `\(x:int) (y:int) -> x + y` takes its arguments one at a time.
+fn binder_expr<'a>(
+    start: impl Fn(&'a str) -> IResult<&'a str, (), Err>,
+    make: fn(String, Box<Expr>, Box<Expr>) -> ExprKind,
+) -> impl Fn(&'a str) -> IResult<&'a str, Expr, Err> {
+    move |input| {
+        let (rest, ()) = start(input)?;
+        let (rest, params) = many1(arg)(rest)?;
+        let (rest, ()) = symbol(ARROW)(rest)?;
+        let (rest, body) = expr(rest)?;
+        let span = span_between(input, rest);
+        let expr = params.into_iter().rev().fold(body, |body, (x, t)| Expr::new(make(x, Box::new(t), Box::new(body)), span));
+        Ok((rest, expr))
+    }
+}
+
+const ARROW: &str = "->";
+
+/// A function body or a REPL expression, which may be followed by `where` and some local
+/// definitions separated by commas. Each definition can use the ones before it.
+fn body_expr(input: &str) -> IResult<&str, Expr, Err> {
+    let start = input;
+    let (input, body) = expr(input)?;
+    let (input, bindings) = match keyword("where")(input) {
+        // Anything after `where` has to be a definition
+        Ok((input, ())) => cut(separated_list1(symbol(","), binding))(input)?,
+        Err(nom::Err::Error(_)) => return Ok((input, body)),
+        Err(e) => return Err(e),
+    };
+    // Equivalent to nesting them with `let`, the first one outermost
+    let span = span_between(start, input);
+    let expr = bindings.into_iter().rev().fold(body, |body, (x, t, v)| {
+        Expr::new(ExprKind::Let(x, t.map(Box::new), Box::new(v), Box::new(body)), span)
+    });
+    Ok((input, expr))
+}
+
+/// `x = value` or `x : type = value`
+fn binding(input: &str) -> IResult<&str, (String, Option<Expr>, Expr), Err> {
+    let (input, name) = word_owned(input)?;
+    let (input, typ) = opt(preceded(symbol(":"), expr))(input)?;
+    let (input, ()) = symbol("=")(input)?;
+    let (input, value) = expr(input)?;
+    Ok((input, (name, typ, value)))
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Infix {
+    And,
+    Or,
+    Call(&'static str),
+}
+
+/// Infix operators, grouped by precedence level from loosest to tightest.
+/// Within a level, operators associate to the left. Where one operator is a
+/// prefix of another, the longer one must come first.
+pub(crate) const INFIX_OPS: &[&[(&str, Infix)]] = &[
+    &[("||", Infix::Or)],
+    &[("&&", Infix::And)],
+    &[
+        ("==", Infix::Call("equals")),
+        ("<=", Infix::Call("le")),
+        ("<", Infix::Call("lt")),
+        (">=", Infix::Call("ge")),
+        (">", Infix::Call("gt")),
+    ],
+    &[("+", Infix::Call("add")), ("-", Infix::Call("sub"))],
+    &[("*", Infix::Call("mul")), ("/", Infix::Call("div")), ("%", Infix::Call("mod"))],
+];
+
+impl Infix {
+    fn apply(self, lhs: Expr, rhs: Expr) -> Expr {
+        let span = match (lhs.span, rhs.span) {
+            (Some(a), Some(b)) => a.to(b),
+            _ => unreachable!("parsed expressions always have spans"),
+        };
+        let kind = match self {
+            Infix::And => ExprKind::And(Box::new(lhs), Box::new(rhs)),
+            Infix::Or => ExprKind::Or(Box::new(lhs), Box::new(rhs)),
+            Infix::Call(f) => ExprKind::Call(f.to_owned(), vec![], vec![lhs, rhs]),
+        };
+        Expr::new(kind, span)
+    }
+}
+
+fn binary_expr(level: usize, input: &str) -> IResult<&str, Expr, Err> {
+    if level == INFIX_OPS.len() {
+        return prefix_expr(input);
+    }
+    let (mut input, mut lhs) = binary_expr(level + 1, input)?;
+    loop {
+        match infix_op(INFIX_OPS[level])(input) {
+            Ok((input2, (sym, op))) => {
+                // Once there's an operator, there's no going back to try something else
+                let (input3, rhs) = cut(|i| binary_expr(level + 1, i))(input2).map_err(|e| match e {
+                    nom::Err::Failure(e) if e.remaining == input2.len() => nom::Err::Failure(Err {
+                        remaining: input.len(),
+                        message: format!("Expected an expression after {}", sym),
+                    }),
+                    e => e,
+                })?;
+                lhs = op.apply(lhs, rhs);
+                input = input3;
+            }
+            Err(nom::Err::Error(_)) => return Ok((input, lhs)),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn prefix_expr(input: &str) -> IResult<&str, Expr, Err> {
+    alt((
+        spanned(map(preceded(symbol("-"), prefix_expr), |x| ExprKind::Call("neg".to_owned(), vec![], vec![x]))),
+        application,
+    ))(input)
+}
+
+fn application(input: &str) -> IResult<&str, Expr, Err> {
+    alt((spanned(tag_with_value), word_with_args, applied_expr))(input)
+}
+
+/// Anything other than a name can be applied to arguments too, as in `(\(x:int) -> x) 3` or `r.f 3`
+fn applied_expr(input: &str) -> IResult<&str, Expr, Err> {
+    let (rest, g) = tight_expr(input)?;
+    let (rest, xs) = many0(tight_expr)(rest)?;
+    if xs.is_empty() {
+        return Ok((rest, g));
+    }
+    Ok((rest, Expr::new(ExprKind::Apply(Box::new(g), xs), span_between(input, rest))))
+}
+
+fn if_expr(input: &str) -> IResult<&str, Expr, Err> {
+    spanned(if_expr_kind)(input)
+}
+
+fn if_expr_kind(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (input, ()) = keyword("if")(input)?;
+    let (input, cond) = expr(input)?;
+    let (input, ()) = keyword("then")(input)?;
+    let (input, then_expr) = expr(input)?;
+    let (input, ()) = keyword("else")(input)?;
+    let (input, else_expr) = expr(input)?;
+    Ok((input, ExprKind::If(Box::new(cond), Box::new(then_expr), Box::new(else_expr))))
+}
+
+fn let_expr(input: &str) -> IResult<&str, Expr, Err> {
+    spanned(let_expr_kind)(input)
+}
+
+fn let_expr_kind(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (input, ()) = keyword("let")(input)?;
+    let (input, (name, typ, value)) = binding(input)?;
+    let (input, ()) = keyword("in")(input)?;
+    let (input, body) = expr(input)?;
+    Ok((input, ExprKind::Let(name, typ.map(Box::new), Box::new(value), Box::new(body))))
 }
 
+fn match_expr(input: &str) -> IResult<&str, Expr, Err> {
+    spanned(match_expr_kind)(input)
+}
+
+fn match_expr_kind(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (input, ()) = keyword("match")(input)?;
+    let (input, x) = expr(input)?;
+    let (input, arms) = delimited(symbol("{"), separated_list0(symbol(","), arm), symbol("}"))(input)?;
+    Ok((input, ExprKind::Match(Box::new(x), arms)))
+}
+
+/// `#a y -> body`. Leaving out the name means the tag's value isn't used.
+fn arm(input: &str) -> IResult<&str, (String, String, Expr), Err> {
+    let (input, a) = tag_name(input)?;
+    let (input, y) = opt(word_owned)(input)?;
+    let (input, ()) = symbol(ARROW)(input)?;
+    let (input, body) = expr(input)?;
+    Ok((input, (a, y.unwrap_or_else(|| "_".to_owned()), body)))
+}
+
+/// Field projections bind tighter than anything else, so `f r.x` passes `r.x` to `f`
 fn tight_expr(input: &str) -> IResult<&str, Expr, Err> {
-    alt((number, var, delimited(symbol("("), expr, symbol(")")), array))(input)
+    let start = input;
+    let (mut input, mut e) = alt((spanned(number), spanned(string), spanned(var), parens, spanned(array), spanned(braces), spanned(bare_tag)))(input)?;
+    while let Ok((rest, x)) = preceded(tagv("."), word_owned)(input) {
+        e = Expr::new(ExprKind::Field(Box::new(e), x), span_between(start, rest));
+        input = rest;
+    }
+    Ok((input, e))
+}
+
+/// The span includes the brackets, so that an operator's span covers all of its operands
+fn parens(input: &str) -> IResult<&str, Expr, Err> {
+    let (rest, e) = delimited(symbol("("), expr, symbol(")"))(input)?;
+    Ok((rest, Expr { span: Some(span_between(input, rest)), ..e }))
+}
+
+/// Elements are tight expressions, the same as function arguments, so `[f x]` has two elements
+fn array(input: &str) -> IResult<&str, ExprKind, Err> {
+    map(delimited(symbol("["), many0(tight_expr), symbol("]")), ExprKind::Array)(input)
+}
+
+/// A record `{x = 1, y = 2}`, a record type `{x:int, y:int}` or a sum type `{a:int | b:string}`.
+/// A sum type with fewer than two tags starts with a bar, as in `{| a:int}`, and `{}` is the record
+/// type with no fields.
+fn braces(input: &str) -> IResult<&str, ExprKind, Err> {
+    delimited(
+        symbol("{"),
+        alt((
+            map(separated_list1(symbol(","), field_value), ExprKind::Record),
+            sum_type,
+            map(separated_list0(symbol(","), field_type), ExprKind::RecordType),
+        )),
+        symbol("}"),
+    )(input)
+}
+
+fn field_value(input: &str) -> IResult<&str, (String, Expr), Err> {
+    let (input, x) = word_owned(input)?;
+    let (input, ()) = symbol("=")(input)?;
+    let (input, value) = expr(input)?;
+    Ok((input, (x, value)))
 }
 
-fn array(input: &str) -> IResult<&str, Expr, Err> {
-    map(delimited(symbol("["), many0(expr), symbol("]")), Expr::Array)(input)
+fn field_type(input: &str) -> IResult<&str, (String, Expr), Err> {
+    let (input, x) = word_owned(input)?;
+    let (input, ()) = symbol(":")(input)?;
+    let (input, typ) = expr(input)?;
+    Ok((input, (x, typ)))
+}
+
+fn sum_type(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (rest, bar) = opt(symbol("|"))(input)?;
+    let (rest, tags) = separated_list0(symbol("|"), tag_type)(rest)?;
+    if bar.is_none() && tags.len() < 2 {
+        return Err(nom::Err::Error(Err {
+            remaining: input.len(),
+            message: "Expected a sum type".to_owned(),
+        }));
+    }
+    Ok((rest, ExprKind::SumType(tags)))
+}
+
+/// A tag written without a type has the value `[]`, whose type is `true`
+fn tag_type(input: &str) -> IResult<&str, (String, Expr), Err> {
+    let (input, a) = word_owned(input)?;
+    let (input, typ) = opt(preceded(symbol(":"), expr))(input)?;
+    Ok((input, (a, typ.unwrap_or_else(|| Expr::var("true")))))
+}
+
+/// `#a x`. Like a function argument, the value has to be a tight expression.
+fn tag_with_value(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (input, a) = tag_name(input)?;
+    let (input, x) = tight_expr(input)?;
+    Ok((input, ExprKind::Tag(a, Box::new(x))))
+}
+
+/// `#a` on its own is short for `#a []`
+fn bare_tag(input: &str) -> IResult<&str, ExprKind, Err> {
+    map(tag_name, |a| ExprKind::Tag(a, Box::new(ExprKind::Array(vec![]).into())))(input)
+}
+
+fn tag_name(input: &str) -> IResult<&str, String, Err> {
+    preceded(tagv("#"), word_owned)(input)
 }
 
 fn word_with_args(input: &str) -> IResult<&str, Expr, Err> {
+    spanned(word_with_args_kind)(input)
+}
+
+fn word_with_args_kind(input: &str) -> IResult<&str, ExprKind, Err> {
     let (input, name) = word_owned(input)?;
-    let (input, params) = many1(tight_expr)(input)?;
-    Ok((input, Expr::Call(name, params)))
+    let (input, params) = many1(alt((hidden("="), map(tight_expr, Param::Normal))))(input)?;
+    let (hidden, args) = split_params(params);
+    Ok((input, ExprKind::Call(name, hidden, args)))
 }
 
-fn var(input: &str) -> IResult<&str, Expr, Err> {
-    map(word_owned, Expr::Var)(input)
+fn var(input: &str) -> IResult<&str, ExprKind, Err> {
+    map(word_owned, ExprKind::Var)(input)
 }
 
-fn number(input: &str) -> IResult<&str, Expr, Err> {
+/// A double-quoted string. Supports the escapes `\"`, `\\`, `\n`, `\r`, `\t`, `\0` and `\u{...}`.
+fn string(input: &str) -> IResult<&str, ExprKind, Err> {
+    let (mut input, ()) = tagv("\"")(input)?;
+    let mut result = String::new();
+    loop {
+        let mut chars = input.chars();
+        match chars.next() {
+            None => return Err(string_err(input, "Unterminated string")),
+            Some('"') => {
+                input = chars.as_str();
+                break;
+            }
+            Some('\\') => {
+                let c = match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('u') => {
+                        let rest = chars.as_str();
+                        let code = rest.strip_prefix('{')
+                            .and_then(|r| r.find('}').map(|end| &r[..end]))
+                            .ok_or_else(|| string_err(input, "Expected \\u{...}"))?;
+                        let c = u32::from_str_radix(code, 16).ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| string_err(input, "Invalid unicode escape"))?;
+                        chars = rest[code.len() + 2..].chars();
+                        c
+                    }
+                    _ => return Err(string_err(input, "Unknown escape")),
+                };
+                result.push(c);
+            }
+            Some(c) => result.push(c),
+        }
+        input = chars.as_str();
+    }
+    let (input, ()) = whitespace(input)?;
+    Ok((input, ExprKind::Str(result)))
+}
+
+fn string_err(input: &str, message: &str) -> nom::Err<Err> {
+    nom::Err::Failure(Err {
+        remaining: input.len(),
+        message: message.to_owned(),
+    })
+}
+
+fn number(input: &str) -> IResult<&str, ExprKind, Err> {
     map(terminated(digit1, whitespace), |s: &str| {
-        Expr::Int(s.parse().unwrap())
+        ExprKind::Int(s.parse().unwrap())
     })(input)
 }
 
@@ -91,7 +495,6 @@ fn symbol<'a, 'b: 'a>(sym: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (),
     }
 }
 
-/*
 fn keyword<'a, 'b: 'a>(kw: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (), Err> {
     move |input| {
         let (input2, w) = word(input).map_err(|e| decorate(e, format!("Expected '{}'", kw)))?;
@@ -105,7 +508,27 @@ fn keyword<'a, 'b: 'a>(kw: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (),
         }
     }
 }
-*/
+
+fn infix_op<'a>(ops: &'static [(&'static str, Infix)]) -> impl Fn(&'a str) -> IResult<&'a str, (&'static str, Infix), Err> {
+    move |input| {
+        // Otherwise the arrow would get read as a minus sign
+        if input.starts_with(ARROW) {
+            return Err(nom::Err::Error(Err {
+                remaining: input.len(),
+                message: "Expected operator".to_owned(),
+            }));
+        }
+        for (sym, op) in ops {
+            if let Ok((input2, ())) = symbol(sym)(input) {
+                return Ok((input2, (sym, *op)));
+            }
+        }
+        Err(nom::Err::Error(Err {
+            remaining: input.len(),
+            message: "Expected operator".to_owned(),
+        }))
+    }
+}
 
 fn tagv<'a, 'b: 'a>(t: &'b str) -> impl Fn(&'a str) -> IResult<&'a str, (), Err> {
     move |input| value((), tag(t))(input)
@@ -122,8 +545,24 @@ fn word(input: &str) -> IResult<&str, &str, Err> {
     )(input)
 }
 
+/// Words that can't be used as names
+const KEYWORDS: &[&str] = &["if", "then", "else", "let", "in", "where", "pi", "match"];
+
 fn word_owned(input: &str) -> IResult<&str, String, Err> {
-    map(word, str::to_owned)(input).map_err(|e| decorate(e, "word"))
+    let (input2, w) = word(input).map_err(|e| decorate(e, "word"))?;
+    if KEYWORDS.contains(&w) {
+        Err(nom::Err::Error(Err {
+            remaining: input.len(),
+            message: format!("'{}' is a keyword", w),
+        }))
+    } else if w.starts_with(|c: char| c.is_ascii_digit()) {
+        Err(nom::Err::Error(Err {
+            remaining: input.len(),
+            message: "Names can't start with a digit".to_owned(),
+        }))
+    } else {
+        Ok((input2, w.to_owned()))
+    }
 }
 
 //////////////
@@ -137,8 +576,7 @@ struct Err {
 
 #[derive(Debug)]
 pub struct ParseErr {
-    pub text: String,
-    pub remaining: usize,
+    pub span: Span,
     pub message: String,
 }
 
@@ -201,10 +639,15 @@ fn decorate(err: nom::Err<Err>, extra: impl fmt::Display) -> nom::Err<Err> {
 }
 
 impl ParseErr {
-    fn new(e: Err, text: &str) -> Self {
+    fn new(e: Err, text: &str, offset: usize) -> Self {
+        let pos = text.len() - e.remaining;
+        // Covers the character the error is at, which may be more than one byte
+        let width = text[pos..].chars().next().map_or(0, char::len_utf8);
         ParseErr {
-            text: text.to_owned(),
-            remaining: e.remaining,
+            span: Span {
+                start: offset + pos,
+                end: offset + pos + width,
+            },
             message: e.message,
         }
     }
@@ -212,15 +655,86 @@ impl ParseErr {
 
 impl fmt::Display for ParseErr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let pos = self.text.len() - self.remaining;
-        write!(
-            f,
-            "{}####{} {}",
-            &self.text[..pos],
-            &self.text[pos..],
-            self.message
-        )
+        write!(f, "{}", self.message)
     }
 }
 
 impl std::error::Error for ParseErr {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the expression as the body of a declaration
+    fn body(text: &str) -> Result<Expr, ParseErr> {
+        Ok(parse(&format!("x : t = {};", text), 0)?.decls.remove(0).1.body.unwrap())
+    }
+
+    fn parses_as(text: &str, expected: &str) {
+        assert_eq!(body(text).unwrap(), body(expected).unwrap(), "{}", text);
+    }
+
+    #[test]
+    fn if_extends_as_far_as_possible() {
+        parses_as("if a then b else f c", "if a then b else (f c)");
+        parses_as("if a then if b then c else d else e", "if a then (if b then c else d) else e");
+        assert!(body("if a then b").is_err());
+        assert!(body("if").is_err());
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        parses_as("a || b && c", "a || (b && c)");
+        parses_as("a && b || c", "(a && b) || c");
+        parses_as("a || b || c", "(a || b) || c");
+        parses_as("f a && g b", "(f a) && (g b)");
+        parses_as("if a then b else c || d", "if a then b else (c || d)");
+        assert!(matches!(body("a && b").unwrap().kind, ExprKind::And(..)));
+    }
+
+    #[test]
+    fn arithmetic_binds_tighter_than_comparisons() {
+        parses_as("1 + 2 * 3 - 4", "(1 + (2 * 3)) - 4");
+        parses_as("a < b && c >= d", "(a < b) && (c >= d)");
+        parses_as("a <= b", "le a b");
+        parses_as("-a * b", "(neg a) * b");
+        parses_as("a - -b", "sub a (neg b)");
+        parses_as("f x + 1", "(f x) + 1");
+    }
+
+    #[test]
+    fn string_escapes() {
+        let s = |text| match body(text).unwrap().kind {
+            ExprKind::Str(s) => s,
+            e => panic!("{:?}", e),
+        };
+        assert_eq!(s(r#""a\"b\\c""#), "a\"b\\c");
+        assert_eq!(s(r#""\n\r\t\0""#), "\n\r\t\0");
+        assert_eq!(s(r#""\u{41}\u{e9}\u{1F600}""#), "A\u{e9}\u{1F600}");
+        assert_eq!(s("\"é\""), "é");
+        let message = |text| body(text).unwrap_err().message;
+        assert_eq!(message(r#""\q""#), "Unknown escape");
+        assert_eq!(message(r#""\u41""#), "Expected \\u{...}");
+        assert_eq!(message(r#""\u{110000}""#), "Invalid unicode escape");
+        assert_eq!(message(r#""abc"#), "Unterminated string");
+    }
+
+    #[test]
+    fn where_is_let_with_the_first_definition_outermost() {
+        parses_as("x + y where x = 1, y : int = x", "let x = 1 in let y : int = x in x + y");
+        parses_as("let x = 1 in x where y = 2", "let y = 2 in let x = 1 in x");
+        assert!(body("x where").is_err());
+        assert!(body("x where y = 1,").is_err());
+        assert!(body("(x where y = 1)").is_err());
+    }
+
+    #[test]
+    fn missing_operand_is_reported_at_the_operator() {
+        let e = parse("f (x:int) : int = x +;", 0).unwrap_err();
+        assert_eq!(e.message, "Expected an expression after +");
+        assert_eq!(e.span.start, 20);
+        let e = parse_expr("1 + (2 * )", 0).unwrap_err();
+        assert_eq!(e.message, "Expected an expression after *");
+        assert_eq!(e.span.start, 7);
+    }
+}
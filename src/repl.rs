@@ -0,0 +1,178 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::ast::{Decl, Script};
+use crate::combine::{self, Program};
+use crate::eval::EvalState;
+use crate::source::Sources;
+use crate::{all, builtins, one, parse, typecheck, Error};
+
+/// Everything that has been declared so far. Declarations are only kept if the whole program
+/// still combines and type checks with them added.
+pub struct Repl {
+    sources: Sources,
+    prelude: Script,
+    /// Kept so that declarations can't reuse its names
+    stdlib: Script,
+    /// Starts off as the standard library
+    decls: Vec<(String, Decl)>,
+    program: Program,
+    /// Kept between inputs, so that global values are only computed once
+    state: EvalState,
+    /// The fuel each input gets
+    fuel: Option<u64>,
+    inputs: usize,
+}
+
+impl Repl {
+    pub fn new(state: EvalState) -> Result<Self, Vec<Error>> {
+        let mut sources = Sources::new();
+        let (prelude, stdlib) = builtins(&mut sources)?;
+        let program = combine::combine(&prelude, &stdlib).map_err(all)?;
+        Ok(Repl {
+            sources,
+            prelude,
+            decls: stdlib.decls.clone(),
+            stdlib,
+            program,
+            fuel: state.fuel,
+            state,
+            inputs: 0,
+        })
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("> ");
+            io::stdout().flush()?;
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            let line = line.trim();
+            if line == ":quit" || line == ":q" {
+                break;
+            }
+            for e in self.handle(line).err().unwrap_or_default() {
+                eprintln!("{}", self.sources.render(e.span(), &e.to_string()));
+            }
+        }
+        println!();
+        Ok(())
+    }
+
+    fn handle(&mut self, line: &str) -> Result<(), Vec<Error>> {
+        if line.is_empty() {
+            Ok(())
+        } else if let Some(text) = line.strip_prefix(":type ") {
+            let expr = parse::parse_expr(text, self.add_input(text)).map_err(one)?;
+            println!("{}", typecheck::type_of(&self.program, &expr).map_err(one)?);
+            Ok(())
+        } else if let Some(filename) = line.strip_prefix(":load ") {
+            let filename = filename.trim();
+            let text = fs::read_to_string(filename).map_err(|e| one(Error::Io(filename.to_owned(), e)))?;
+            let script = parse::parse(&text, self.sources.add(filename, &text)).map_err(one)?;
+            self.declare(script)
+        } else if line == ":reset" {
+            let mut state = EvalState::new(self.fuel);
+            state.max_depth = self.state.max_depth;
+            state.stack_limit = self.state.stack_limit;
+            *self = Repl::new(state)?;
+            Ok(())
+        } else if line.starts_with(':') {
+            Err(one(Error::Repl(format!("unknown command {}; try :type, :load, :reset or :quit", line))))
+        } else {
+            self.declare_or_evaluate(line)
+        }
+    }
+
+    /// Declarations end with a semicolon, which an expression can't, so whichever parse gets
+    /// further is the one the user meant.
+    fn declare_or_evaluate(&mut self, text: &str) -> Result<(), Vec<Error>> {
+        let offset = self.add_input(text);
+        let script_err = match parse::parse(text, offset) {
+            Ok(script) => return self.declare(script),
+            Err(e) => e,
+        };
+        let expr = match parse::parse_expr(text, offset) {
+            Ok(expr) => expr,
+            Err(e) if e.span.start >= script_err.span.start => return Err(one(e)),
+            Err(_) => return Err(one(script_err)),
+        };
+        let typ = typecheck::type_of(&self.program, &expr).map_err(one)?;
+        self.state.fuel = self.fuel;
+        let val = self.program.eval_closed(&expr, &mut self.state).map_err(one)?;
+        println!("{} : {}", val, typ);
+        Ok(())
+    }
+
+    fn declare(&mut self, script: Script) -> Result<(), Vec<Error>> {
+        combine::check_not_reserved(&self.stdlib, &script).map_err(all)?;
+        let mut decls = self.decls.clone();
+        decls.extend(script.decls);
+        let script = Script { decls };
+        let program = combine::combine(&self.prelude, &script).map_err(all)?;
+        typecheck::type_check(&program).map_err(all)?;
+        self.decls = script.decls;
+        self.program = program;
+        Ok(())
+    }
+
+    fn add_input(&mut self, text: &str) -> usize {
+        self.inputs += 1;
+        self.sources.add(&format!("<input {}>", self.inputs), text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(r: Result<(), Vec<Error>>) -> Vec<String> {
+        r.err().unwrap_or_default().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn declarations_are_kept_for_later_inputs() {
+        let mut repl = Repl::new(EvalState::new(None)).unwrap();
+        assert!(repl.handle("sq (x:int) : int = x * x;").is_ok());
+        assert!(repl.handle("sq 3").is_ok());
+        assert!(repl.handle("quad (x:int) : int = sq (sq x);").is_ok());
+        assert!(repl.program.funcs.contains_key("quad"));
+    }
+
+    #[test]
+    fn declarations_that_fail_to_check_are_dropped() {
+        let mut repl = Repl::new(EvalState::new(None)).unwrap();
+        assert_eq!(messages(repl.handle("bad : int = \"a\";")), ["in bad: expected the body to have type int, but it has type string"]);
+        assert!(!repl.program.funcs.contains_key("bad"));
+        assert!(repl.handle("bad : int = 1;").is_ok());
+        assert_eq!(messages(repl.handle("bad : int = 2;")), ["bad has already been declared"]);
+        assert!(repl.handle(":reset").is_ok());
+        assert!(!repl.program.funcs.contains_key("bad"));
+    }
+
+    #[test]
+    fn the_error_comes_from_whichever_parse_got_further() {
+        let mut repl = Repl::new(EvalState::new(None)).unwrap();
+        let mut location = |text| {
+            let e = repl.handle(text).unwrap_err();
+            repl.sources.render(e[0].span(), "").lines().nth(1).unwrap().to_owned()
+        };
+        assert_eq!(location("1 + "), " --> <input 1>:1:3");
+        assert_eq!(location("f (x:int) : int = x +;"), " --> <input 2>:1:21");
+        assert_eq!(messages(repl.handle(":frobnicate")), ["unknown command :frobnicate; try :type, :load, :reset or :quit"]);
+    }
+
+    #[test]
+    fn each_input_gets_the_same_fuel() {
+        let mut repl = Repl::new(EvalState::new(Some(300))).unwrap();
+        assert!(repl.handle("count (n:int) : int = if n <= 0 then 0 else count (n - 1);").is_ok());
+        assert!(repl.handle("count 10").is_ok());
+        assert!(repl.handle("count 10").is_ok());
+        assert!(repl.handle("count 100").is_err());
+        assert!(repl.handle("count 10").is_ok());
+    }
+}
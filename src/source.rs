@@ -0,0 +1,96 @@
+use crate::ast::Span;
+
+/// All the source text that has been parsed. Each file is given its own range of offsets,
+/// so a span on its own is enough to find the file it came from.
+pub struct Sources {
+    files: Vec<SourceFile>,
+}
+
+struct SourceFile {
+    name: String,
+    text: String,
+    offset: usize,
+}
+
+impl Sources {
+    pub fn new() -> Self {
+        Sources { files: vec![] }
+    }
+
+    /// Registers a file, returning the offset that its spans start at
+    pub fn add(&mut self, name: &str, text: &str) -> usize {
+        // Leave a gap so that a span at the very end of one file isn't mistaken for the start of the next
+        let offset = self.files.last().map_or(0, |f| f.offset + f.text.len() + 1);
+        self.files.push(SourceFile {
+            name: name.to_owned(),
+            text: text.to_owned(),
+            offset,
+        });
+        offset
+    }
+
+    /// Formats a message along with the location it refers to and an excerpt of the source, e.g.
+    ///
+    /// ```text
+    /// error: no such variable x
+    ///  --> script.jd:2:16
+    ///   |
+    /// 2 | f (y:int) : int = x;
+    ///   |                   ^
+    /// ```
+    pub fn render(&self, span: Option<Span>, message: &str) -> String {
+        let mut result = format!("error: {}", message);
+        let file = span.and_then(|span| self.files.iter().rev().find(|f| f.offset <= span.start).map(|f| (f, span)));
+        if let Some((file, span)) = file {
+            let start = floor_char_boundary(&file.text, span.start - file.offset);
+            let end = floor_char_boundary(&file.text, span.end - file.offset).max(start);
+
+            let line_start = file.text[..start].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = file.text[start..].find('\n').map_or(file.text.len(), |i| start + i);
+            let line = &file.text[line_start..line_end];
+            let line_no = file.text[..start].matches('\n').count() + 1;
+            let column = file.text[line_start..start].chars().count() + 1;
+            // Spans covering several lines only get underlined on the first one
+            let width = file.text[start..end.min(line_end)].chars().count().max(1);
+
+            let gutter = " ".repeat(line_no.to_string().len());
+            result += &format!("\n{}--> {}:{}:{}", gutter, file.name, line_no, column);
+            result += &format!("\n{} |", gutter);
+            result += &format!("\n{} | {}", line_no, line);
+            result += &format!("\n{} | {}{}", gutter, " ".repeat(column - 1), "^".repeat(width));
+        }
+        result
+    }
+}
+
+/// The nearest position at or before `i` that's within the text and not in the middle of a character
+fn floor_char_boundary(text: &str, i: usize) -> usize {
+    let mut i = i.min(text.len());
+    while !text.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn parse_error_at_a_non_ascii_character() {
+        let mut sources = Sources::new();
+        let text = "f (x:int) : int = x é;";
+        let e = parse::parse(text, sources.add("script.jd", text)).unwrap_err();
+        assert_eq!(&text[e.span.start..e.span.end], "é");
+        assert!(sources.render(Some(e.span), "oops").ends_with("\n  |                     ^"));
+    }
+
+    #[test]
+    fn spans_in_the_middle_of_a_character_are_clamped() {
+        let mut sources = Sources::new();
+        let offset = sources.add("script.jd", "x é y");
+        let rendered = sources.render(Some(Span { start: offset + 3, end: offset + 4 }), "oops");
+        assert!(rendered.ends_with("1 | x é y\n  |   ^"), "{}", rendered);
+    }
+}
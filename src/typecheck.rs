@@ -1,154 +1,889 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
-use num_bigint::{Sign};
+use num_bigint::{BigInt, Sign};
+use num_traits::{One, Zero};
 
-use crate::ast::Expr;
+use crate::ast::{Expr, ExprKind, Sig, Span};
 use crate::combine::{Program,Func};
+use crate::eval::{EvalState, Val};
+
+/// How many evaluation steps the type checker will spend on a single closed type expression
+const TYPE_EVAL_FUEL: u64 = 1_000;
+/// How deeply the functions called by a type expression may nest
+const TYPE_EVAL_DEPTH: usize = 100;
 
 struct CheckedFunc {
-    args: Vec<(String,Expr)>,
-    ret: Expr,
+    sigs: Vec<Sig>,
+    prelude: bool,
+}
+
+/// The functions whose signatures are known so far. Also holds on to the program, so that closed
+/// type expressions can be evaluated.
+struct Funcs<'a> {
+    program: &'a Program,
+    checked: HashMap<String, CheckedFunc>,
+    state: RefCell<EvalState>,
 }
 
 #[derive(Debug)]
 pub enum TypeError {
-    ExpectedArgToBeOfTypeType(String, Expr, Expr),
+    ExpectedArgToBeOfTypeType(String, Box<Expr>, Box<Expr>),
     DuplicateArgName(String),
-    CannotCoerceReturnType(Expr, Expr),
-    CannotCoerceArgumentType(String, usize, Expr, Expr, Expr),
+    CannotCoerceReturnType(Box<Expr>, Box<Expr>),
+    CannotCoerceArgumentType(String, usize, Box<Expr>, Box<Expr>, Box<Expr>),
+    CannotCoerceHiddenArgType(String, String, Box<Expr>, Box<Expr>, Box<Expr>),
+    NoSuchHiddenArg(String, String),
+    HiddenArgOutOfOrder(String, String),
+    CannotInferHiddenArg(String, String),
+    ConflictingHiddenArg(String, String, Vec<Expr>),
+    HiddenArgUsedInBody(String),
+    ExpectedConditionToBeBool(Box<Expr>, Box<Expr>),
+    IncompatibleBranchTypes(Box<Expr>, Box<Expr>),
+    ExpectedOperandToBeBool(Box<Expr>, Box<Expr>),
+    ExpectedLocalToBeOfTypeType(String, Box<Expr>, Box<Expr>),
+    CannotCoerceLocalType(String, Box<Expr>, Box<Expr>),
+    /// A part of a function type that isn't a type
+    ExpectedType(Box<Expr>, Box<Expr>),
+    NotAFunction(String, Box<Expr>),
+    /// A function used as a value that has hidden arguments or more than one signature
+    FuncNotAValue(String),
+    /// A field or tag that's listed more than once in the same record or type
+    DuplicateField(String),
+    NotARecord(Box<Expr>, Box<Expr>),
+    NoSuchField(String, Box<Expr>),
+    NotASum(Box<Expr>, Box<Expr>),
+    /// A match arm for a tag that the type doesn't have
+    NoSuchTag(String, Box<Expr>),
+    DuplicateArm(String),
+    /// The tags of the type that the match has no arm for
+    MissingArms(Vec<String>, Box<Expr>),
+    /// The type of an arm can't mention the tag's value, since it goes out of scope
+    ArmTypeDependsOnValue(String, Box<Expr>),
     NoSuchFunc(String),
     NoSuchVar(String),
     WrongNumberOfArgs(String, usize, usize),
+    NoMatchingSignature(String, Vec<Expr>),
+    AmbiguousCall(String, Vec<Expr>, Vec<Sig>),
     WhenChecking(String, Box<TypeError>),
+    WhenCheckingSignature(usize, Box<TypeError>),
+    /// The innermost expression that was being checked when the error happened
+    At(Span, Box<TypeError>),
 }
 
-pub fn type_check(program: &Program) -> Result<(), TypeError> {
-    let mut checked_funcs = HashMap::new();
-    for name in &program.order {
-        if let Some(func) = program.funcs.get(name) {
-            let checked = check_func(func, &checked_funcs).map_err(|e|TypeError::WhenChecking(name.clone(),Box::new(e)))?;
-            checked_funcs.insert(name.clone(), checked);
-        } else {
-            return Err(TypeError::NoSuchFunc(name.clone()));
+/// Checks every function, carrying on past any that fail. Each error is wrapped in `WhenChecking`
+/// with the name of the function it belongs to, and they come in the order the functions were declared.
+pub fn type_check(program: &Program) -> Result<(), Vec<TypeError>> {
+    let mut checked_funcs = Funcs {
+        program,
+        checked: HashMap::new(),
+        state: RefCell::new(EvalState::new(None)),
+    };
+    let mut errors = vec![];
+    for component in &program.order {
+        // Functions in a recursive group can call each other, so all of their declared signatures
+        // need to be available before any of their bodies get checked. A function whose body fails
+        // to check still keeps its signatures, so that the functions calling it can be checked too.
+        let mut funcs = vec![];
+        for name in component {
+            if let Some(func) = program.funcs.get(name) {
+                checked_funcs.checked.insert(name.clone(), CheckedFunc {
+                    sigs: func.sigs.clone(),
+                    prelude: func.prelude,
+                });
+                funcs.push((name, func));
+            } else {
+                errors.push((0, TypeError::NoSuchFunc(name.clone())));
+            }
+        }
+        for (name, func) in funcs {
+            for e in check_func(func, &checked_funcs) {
+                // Errors that don't come from a particular expression are reported at the declaration
+                errors.push((func.span.start, TypeError::WhenChecking(name.clone(), Box::new(e.at(Some(func.span))))));
+            }
         }
     }
-    Ok(())
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        errors.sort_by_key(|e| e.0);
+        Err(errors.into_iter().map(|e| e.1).collect())
+    }
 }
 
-fn check_func(func: &Func, funcs: &HashMap<String, CheckedFunc>) -> Result<CheckedFunc, TypeError> {
-    if !func.prelude {
-        let mut env = HashMap::new();
+/// Infers the type of an expression that doesn't refer to any local variables. The program must
+/// already have been checked.
+pub fn type_of(program: &Program, expr: &Expr) -> Result<Expr, TypeError> {
+    let funcs = Funcs {
+        program,
+        checked: program.funcs.iter().map(|(name, func)| (name.clone(), CheckedFunc {
+            sigs: func.sigs.clone(),
+            prelude: func.prelude,
+        })).collect(),
+        state: RefCell::new(EvalState::new(None)),
+    };
+    check_expr(expr, &funcs, &HashMap::new())
+}
 
-        for arg in &func.args {
-            check_arg_is_of_type_type(&arg.0, &arg.1, funcs, &env)?;
-            if env.contains_key(&arg.0) {
-                return Err(TypeError::DuplicateArgName(arg.0.clone()));
+/// Returns every error found. Each signature is checked separately.
+fn check_func(func: &Func, funcs: &Funcs) -> Vec<TypeError> {
+    let mut errors = vec![];
+    if !func.prelude {
+        if let Err(e) = check_hidden_args_unused(&func.body, &func.hidden_names) {
+            errors.push(e);
+        }
+        for (i, sig) in func.sigs.iter().enumerate() {
+            if let Err(e) = check_body_against_sig(func, sig, funcs) {
+                if func.sigs.len() > 1 {
+                    errors.push(TypeError::WhenCheckingSignature(i, Box::new(e)));
+                } else {
+                    errors.push(e);
+                }
             }
-            env.insert(arg.0.clone(), arg.1.clone());
         }
+    }
+    errors
+}
 
-        let t = check_expr(&func.body, funcs, &env)?;
-        if !can_coerce_type(&t, &func.ret, funcs, &env) {
-            return Err(TypeError::CannotCoerceReturnType(t, func.ret.clone()));
+/// The body is checked against every signature. The signature may name its arguments differently
+/// from the line with the body, so they get renamed first.
+fn check_body_against_sig(func: &Func, sig: &Sig, funcs: &Funcs) -> Result<(), TypeError> {
+    let mut renaming = HashMap::new();
+    for (name, _) in &sig.hidden {
+        renaming.insert(name.clone(), Expr::var(name));
+    }
+    for (arg, name) in sig.args.iter().zip(&func.arg_names) {
+        renaming.insert(arg.0.clone(), Expr::var(name));
+    }
+
+    let mut env = HashMap::new();
+    for (name, typ) in &sig.hidden {
+        check_arg_is_of_type_type(name, typ, funcs, &env)?;
+        if env.contains_key(name) {
+            return Err(TypeError::DuplicateArgName(name.clone()));
         }
+        env.insert(name.clone(), typ.clone());
+    }
+    for (arg, name) in sig.args.iter().zip(&func.arg_names) {
+        let typ = arg.1.map_vars(&renaming, funcs)?;
+        check_arg_is_of_type_type(name, &typ, funcs, &env)?;
+        if env.contains_key(name) {
+            return Err(TypeError::DuplicateArgName(name.clone()));
+        }
+        env.insert(name.clone(), typ);
     }
 
-    Ok(CheckedFunc {
-        args: func.args.clone(),
-        ret: func.ret.clone(),
-    })
+    let ret = sig.ret.map_vars(&renaming, funcs)?;
+    let t = check_expr(&func.body, funcs, &env)?;
+    if !can_coerce_value(&func.body, &t, &ret, funcs, &env) {
+        return Err(TypeError::CannotCoerceReturnType(Box::new(t), Box::new(ret)).at(func.body.span));
+    }
+    Ok(())
+}
+
+/// The interpreter ignores hidden arguments, so the body may only mention them when filling in
+/// hidden arguments of other calls.
+fn check_hidden_args_unused(expr: &Expr, hidden_names: &[String]) -> Result<(), TypeError> {
+    match &expr.kind {
+        ExprKind::Int(_) | ExprKind::Str(_) => Ok(()),
+        ExprKind::Var(x) => if hidden_names.contains(x) {
+            Err(TypeError::HiddenArgUsedInBody(x.clone()).at(expr.span))
+        } else {
+            Ok(())
+        },
+        ExprKind::Call(f, _, _) if hidden_names.contains(f) => Err(TypeError::HiddenArgUsedInBody(f.clone()).at(expr.span)),
+        ExprKind::Call(_, _, xs) | ExprKind::Array(xs) => {
+            for x in xs {
+                check_hidden_args_unused(x, hidden_names)?;
+            }
+            Ok(())
+        }
+        ExprKind::Apply(g, xs) => {
+            check_hidden_args_unused(g, hidden_names)?;
+            for x in xs {
+                check_hidden_args_unused(x, hidden_names)?;
+            }
+            Ok(())
+        }
+        ExprKind::If(c, a, b) => {
+            check_hidden_args_unused(c, hidden_names)?;
+            check_hidden_args_unused(a, hidden_names)?;
+            check_hidden_args_unused(b, hidden_names)
+        }
+        // The annotation is only a type
+        ExprKind::Let(x, _, v, b) => {
+            check_hidden_args_unused(v, hidden_names)?;
+            let unshadowed: Vec<String> = hidden_names.iter().filter(|h| *h != x).cloned().collect();
+            check_hidden_args_unused(b, &unshadowed)
+        }
+        // The parameter type only gets used for printing
+        ExprKind::Lambda(x, _, b) => {
+            let unshadowed: Vec<String> = hidden_names.iter().filter(|h| *h != x).cloned().collect();
+            check_hidden_args_unused(b, &unshadowed)
+        }
+        // Function types are never evaluated any further
+        ExprKind::Pi(..) => Ok(()),
+        ExprKind::RecordType(fs) => {
+            let mut unshadowed = hidden_names.to_vec();
+            for (x, t) in fs {
+                check_hidden_args_unused(t, &unshadowed)?;
+                unshadowed.retain(|h| h != x);
+            }
+            Ok(())
+        }
+        ExprKind::SumType(fs) | ExprKind::Record(fs) => {
+            for (_, x) in fs {
+                check_hidden_args_unused(x, hidden_names)?;
+            }
+            Ok(())
+        }
+        ExprKind::Tag(_, x) | ExprKind::Field(x, _) => check_hidden_args_unused(x, hidden_names),
+        ExprKind::Match(x, arms) => {
+            check_hidden_args_unused(x, hidden_names)?;
+            for (_, y, b) in arms {
+                let unshadowed: Vec<String> = hidden_names.iter().filter(|h| *h != y).cloned().collect();
+                check_hidden_args_unused(b, &unshadowed)?;
+            }
+            Ok(())
+        }
+        ExprKind::And(a, b) | ExprKind::Or(a, b) => {
+            check_hidden_args_unused(a, hidden_names)?;
+            check_hidden_args_unused(b, hidden_names)
+        }
+    }
 }
 
-fn check_arg_is_of_type_type(name: &str, expr: &Expr, funcs: &HashMap<String, CheckedFunc>, env: &HashMap<String, Expr>) -> Result<(), TypeError> {
+fn check_arg_is_of_type_type(name: &str, expr: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<(), TypeError> {
     let t = check_expr(expr, funcs, env)?;
-    let typ = Expr::Var("type".to_owned());
+    let typ = Expr::var("type");
     if can_coerce_type(&t, &typ, funcs, env) {
         Ok(())
     } else {
-        Err(TypeError::ExpectedArgToBeOfTypeType(name.to_owned(), expr.clone(), t))
+        Err(TypeError::ExpectedArgToBeOfTypeType(name.to_owned(), Box::new(expr.clone()), Box::new(t)))
     }
 }
 
-fn check_expr(expr: &Expr, funcs: &HashMap<String, CheckedFunc>, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
-    match expr {
-        Expr::Int(n) => {
+fn check_expr(expr: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    check_expr_here(expr, funcs, env).map_err(|e| e.at(expr.span))
+}
+
+/// Checks an expression without attaching its span to any errors
+fn check_expr_here(expr: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    match &expr.kind {
+        ExprKind::Int(n) => {
             if n.sign() == Sign::Minus {
-                Ok(Expr::Var("int".to_owned()))
+                Ok(Expr::var("int"))
             } else {
-                Ok(Expr::Var("uint".to_owned()))
+                Ok(Expr::var("uint"))
             }
         }
-        Expr::Var(x) => {
+        ExprKind::Str(_) => Ok(Expr::var("string")),
+        ExprKind::Var(x) => {
             if let Some(t) = env.get(x) {
                 Ok(t.clone())
-            } else if let Some(cf) = funcs.get(x) {
-                if cf.args.len() == 0 {
-                    Ok(cf.ret.clone())
-                } else {
-                    Err(TypeError::WrongNumberOfArgs(x.clone(), cf.args.len(), 0))
-                }
+            } else if funcs.get(x).is_some_and(|cf| !cf.sigs[0].args.is_empty()) {
+                func_value_type(x, funcs)
+            } else if funcs.contains_key(x) {
+                check_call(x, &[], &[], funcs, env)
             } else {
                 Err(TypeError::NoSuchVar(x.clone()))
             }
         }
-        Expr::Call(f, xs) => {
-            if let Some(cf) = funcs.get(f) {
-                if cf.args.len() == xs.len() {
-                    let ts = xs.iter().map(|x|check_expr(x, funcs, env)).collect::<Result<Vec<_>,_>>()?;
-                    let mut var_mapping = HashMap::new();
-                    for i in 0..ts.len() {
-                        let t1 = cf.args[i].1.map_vars(&var_mapping, funcs)?;
-                        if !can_coerce_type(&ts[i], &t1, funcs, env) {
-                            return Err(TypeError::CannotCoerceArgumentType(f.clone(), i, xs[i].clone(), ts[i].clone(), t1));
-                        }
-                        if var_mapping.contains_key(&cf.args[i].0) {
-                            return Err(TypeError::DuplicateArgName(cf.args[i].0.clone()));
-                        }
-                        var_mapping.insert(cf.args[i].0.clone(), xs[i].clone());
-                    }
-                    cf.ret.map_vars(&var_mapping, funcs)
-                } else {
-                    Err(TypeError::WrongNumberOfArgs(f.clone(), cf.args.len(), xs.len()))
+        // Local variables hide functions of the same name
+        ExprKind::Call(f, hs, xs) => match env.get(f) {
+            Some(t) => check_apply(f, t, hs, xs, 0, funcs, env),
+            None => check_call(f, hs, xs, funcs, env),
+        },
+        ExprKind::Apply(g, xs) => {
+            let tg = check_expr(g, funcs, env)?;
+            check_apply(&g.to_string(), &tg, &[], xs, 0, funcs, env)
+        }
+        ExprKind::Array(xs) => {
+            let ts = xs.iter().map(|x|check_expr(x, funcs, env)).collect::<Result<Vec<_>,_>>()?;
+            Ok(Expr::call("tuple", vec![ExprKind::Array(ts).into()]))
+        }
+        ExprKind::If(c, a, b) => {
+            let tc = check_expr(c, funcs, env)?;
+            if !can_coerce_type(&tc, &Expr::var("bool"), funcs, env) {
+                return Err(TypeError::ExpectedConditionToBeBool(c.clone(), Box::new(tc)));
+            }
+            let ta = check_expr(a, funcs, env)?;
+            let tb = check_expr(b, funcs, env)?;
+            join_types(ta, tb, funcs, env)
+        }
+        ExprKind::Let(x, t, v, b) => check_let(x, t.as_deref(), v, b, funcs, env),
+        ExprKind::Lambda(x, t, b) => check_lambda(x, t, b, funcs, env),
+        ExprKind::Pi(x, a, b) => check_pi(x, a, b, funcs, env),
+        ExprKind::RecordType(fs) => check_record_type(fs, funcs, env),
+        ExprKind::SumType(fs) => check_sum_type(fs, funcs, env),
+        ExprKind::Record(fs) => {
+            check_unique_fields(fs)?;
+            let ts = fs.iter().map(|(x, v)| Ok((x.clone(), check_expr(v, funcs, env)?))).collect::<Result<_, TypeError>>()?;
+            Ok(ExprKind::RecordType(ts).into())
+        }
+        // A bare tag's type is written the same way as the tag
+        ExprKind::Tag(a, x) if x.is_empty_array() => Ok(ExprKind::SumType(vec![(a.clone(), Expr::var("true"))]).into()),
+        ExprKind::Tag(a, x) => Ok(ExprKind::SumType(vec![(a.clone(), check_expr(x, funcs, env)?)]).into()),
+        ExprKind::Field(r, x) => check_field(r, x, funcs, env),
+        ExprKind::Match(x, arms) => check_match(x, arms, funcs, env),
+        ExprKind::And(a, b) | ExprKind::Or(a, b) => {
+            check_operand_is_bool(a, funcs, env)?;
+            check_operand_is_bool(b, funcs, env)?;
+            Ok(Expr::var("bool"))
+        }
+    }
+}
+
+fn check_let(x: &str, t: Option<&Expr>, v: &Expr, b: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    let tv = check_expr(v, funcs, env)?;
+    let typ = match t {
+        Some(t) => {
+            let tt = check_expr(t, funcs, env)?;
+            if !can_coerce_type(&tt, &Expr::var("type"), funcs, env) {
+                return Err(TypeError::ExpectedLocalToBeOfTypeType(x.to_owned(), Box::new(t.clone()), Box::new(tt)).at(t.span));
+            }
+            if !can_coerce_value(v, &tv, t, funcs, env) {
+                return Err(TypeError::CannotCoerceLocalType(x.to_owned(), Box::new(tv), Box::new(t.clone())).at(v.span));
+            }
+            t.clone()
+        }
+        None => tv,
+    };
+    let (x, b) = unshadow(x, b, funcs, env)?;
+    let mut body_env = env.clone();
+    body_env.insert(x.clone(), typ);
+    let tb = check_expr(&b, funcs, &body_env)?;
+    // The variable goes out of scope, so the type refers to its value instead
+    let mut var_mapping = local_vars(env);
+    var_mapping.insert(x, v.clone());
+    tb.map_vars(&var_mapping, funcs)
+}
+
+/// The type is a function type whose result type mentions the parameter
+fn check_lambda(x: &str, t: &Expr, b: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    check_arg_is_of_type_type(x, t, funcs, env)?;
+    let (x, b) = unshadow(x, b, funcs, env)?;
+    let mut body_env = env.clone();
+    body_env.insert(x.clone(), t.clone());
+    let tb = check_expr(&b, funcs, &body_env)?;
+    Ok(ExprKind::Pi(x, Box::new(t.clone()), Box::new(tb)).into())
+}
+
+fn check_pi(x: &str, a: &Expr, b: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    let ta = check_expr(a, funcs, env)?;
+    if !can_coerce_type(&ta, &Expr::var("type"), funcs, env) {
+        return Err(TypeError::ExpectedType(Box::new(a.clone()), Box::new(ta)).at(a.span));
+    }
+    let (x, b) = unshadow(x, b, funcs, env)?;
+    let mut body_env = env.clone();
+    body_env.insert(x, a.clone());
+    let tb = check_expr(&b, funcs, &body_env)?;
+    if !can_coerce_type(&tb, &Expr::var("type"), funcs, &body_env) {
+        return Err(TypeError::ExpectedType(Box::new(b.clone()), Box::new(tb)).at(b.span));
+    }
+    Ok(Expr::var("type"))
+}
+
+/// Whichever of the two types the other coerces to
+fn join_types(ta: Expr, tb: Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    if can_coerce_type(&ta, &tb, funcs, env) {
+        Ok(tb)
+    } else if can_coerce_type(&tb, &ta, funcs, env) {
+        Ok(ta)
+    } else {
+        Err(TypeError::IncompatibleBranchTypes(Box::new(ta), Box::new(tb)))
+    }
+}
+
+/// A record type is an `eq` if all its field types are. Each field's type can mention the fields
+/// before it, which it sees as fields of a record that has just those.
+fn check_record_type(fs: &[(String, Expr)], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    check_unique_fields(fs)?;
+    let r = fresh_name("r", env);
+    let mut all_eq = true;
+    for i in 0..fs.len() {
+        let mut inner = env.clone();
+        inner.insert(r.clone(), ExprKind::RecordType(fs[..i].to_vec()).into());
+        let (_, t) = &fields_of(&Expr::var(&r), &fs[..=i], funcs, &inner)?[i];
+        let tt = check_expr(t, funcs, &inner)?;
+        if !can_coerce_type(&tt, &Expr::var("type"), funcs, &inner) {
+            return Err(TypeError::ExpectedType(Box::new(fs[i].1.clone()), Box::new(tt)).at(fs[i].1.span));
+        }
+        all_eq &= can_coerce_type(&tt, &Expr::var("eq"), funcs, &inner);
+    }
+    Ok(Expr::var(if all_eq { "eq" } else { "type" }))
+}
+
+fn check_sum_type(fs: &[(String, Expr)], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    check_unique_fields(fs)?;
+    let mut all_eq = true;
+    for (_, t) in fs {
+        let tt = check_expr(t, funcs, env)?;
+        if !can_coerce_type(&tt, &Expr::var("type"), funcs, env) {
+            return Err(TypeError::ExpectedType(Box::new(t.clone()), Box::new(tt)).at(t.span));
+        }
+        all_eq &= can_coerce_type(&tt, &Expr::var("eq"), funcs, env);
+    }
+    Ok(Expr::var(if all_eq { "eq" } else { "type" }))
+}
+
+fn check_unique_fields(fs: &[(String, Expr)]) -> Result<(), TypeError> {
+    for (i, (x, _)) in fs.iter().enumerate() {
+        if fs[..i].iter().any(|f| &f.0 == x) {
+            return Err(TypeError::DuplicateField(x.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// The types of the fields of `r`, with any mention of an earlier field replaced by that field of
+/// `r`. For example the fields of `{n:uint, xs:vector int n}` have types `uint` and
+/// `vector int r.n`.
+fn fields_of(r: &Expr, fs: &[(String, Expr)], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Vec<(String, Expr)>, TypeError> {
+    let mut var_mapping = local_vars(env);
+    let mut result = vec![];
+    for (x, t) in fs {
+        result.push((x.clone(), t.map_vars(&var_mapping, funcs)?));
+        var_mapping.insert(x.clone(), ExprKind::Field(Box::new(r.clone()), x.clone()).into());
+    }
+    Ok(result)
+}
+
+fn check_field(r: &Expr, x: &str, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    let tr = check_expr(r, funcs, env)?;
+    let fs = match funcs.unfold(&tr, env).kind {
+        ExprKind::RecordType(fs) => fs,
+        _ => return Err(TypeError::NotARecord(Box::new(r.clone()), Box::new(tr))),
+    };
+    match fields_of(r, &fs, funcs, env)?.into_iter().find(|f| f.0 == x) {
+        Some((_, t)) => Ok(t),
+        None => Err(TypeError::NoSuchField(x.to_owned(), Box::new(tr))),
+    }
+}
+
+/// There has to be exactly one arm for each tag of the type. The result is whichever of the arms'
+/// types the others all coerce to, or `false` if the type has no tags and so no values.
+fn check_match(x: &Expr, arms: &[(String, String, Expr)], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    let tx = check_expr(x, funcs, env)?;
+    let tags = match funcs.unfold(&tx, env).kind {
+        ExprKind::SumType(tags) => tags,
+        _ => return Err(TypeError::NotASum(Box::new(x.clone()), Box::new(tx))),
+    };
+    for (i, (a, _, b)) in arms.iter().enumerate() {
+        if !tags.iter().any(|t| &t.0 == a) {
+            return Err(TypeError::NoSuchTag(a.clone(), Box::new(tx)).at(b.span));
+        }
+        if arms[..i].iter().any(|arm| &arm.0 == a) {
+            return Err(TypeError::DuplicateArm(a.clone()).at(b.span));
+        }
+    }
+    let missing: Vec<String> = tags.iter().map(|t| t.0.clone()).filter(|a| !arms.iter().any(|arm| &arm.0 == a)).collect();
+    if !missing.is_empty() {
+        return Err(TypeError::MissingArms(missing, Box::new(tx)));
+    }
+
+    let mut result: Option<Expr> = None;
+    for (a, y, b) in arms {
+        let t = &tags.iter().find(|t| &t.0 == a).expect("checked above").1;
+        let (y, b) = unshadow(y, b, funcs, env)?;
+        let mut arm_env = env.clone();
+        arm_env.insert(y.clone(), t.clone());
+        let tb = check_expr(&b, funcs, &arm_env)?;
+        if tb.mentions(&y) {
+            return Err(TypeError::ArmTypeDependsOnValue(a.clone(), Box::new(tb)).at(b.span));
+        }
+        result = Some(match result {
+            None => tb,
+            Some(t) => join_types(t, tb, funcs, env).map_err(|e| e.at(b.span))?,
+        });
+    }
+    Ok(result.unwrap_or_else(|| Expr::var("false")))
+}
+
+/// Calls a function value, one argument at a time. Each argument gets substituted for the
+/// parameter in the result type. `first` is the position of the first of these arguments in the
+/// call, for error messages.
+fn check_apply(f: &str, tf: &Expr, hs: &[(String, Expr)], xs: &[Expr], first: usize, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    if let Some((h, _)) = hs.first() {
+        return Err(TypeError::NoSuchHiddenArg(f.to_owned(), h.clone()));
+    }
+    let mut t = tf.clone();
+    for (i, x) in xs.iter().enumerate() {
+        let (y, a, b) = match funcs.unfold(&t, env).kind {
+            ExprKind::Pi(y, a, b) => (y, a, b),
+            _ => return Err(TypeError::NotAFunction(f.to_owned(), Box::new(t))),
+        };
+        let tx = check_expr(x, funcs, env)?;
+        if !can_coerce_value(x, &tx, &a, funcs, env) {
+            return Err(TypeError::CannotCoerceArgumentType(f.to_owned(), first + i, Box::new(x.clone()), Box::new(tx), a));
+        }
+        let mut var_mapping = local_vars(env);
+        var_mapping.insert(y, x.clone());
+        t = b.map_vars(&var_mapping, funcs)?;
+    }
+    Ok(t)
+}
+
+/// The type of a function used as a value rather than called, e.g. `pi (x:int) -> vector int x`
+/// for a function declared `f (x:int) : vector int x`. Function values can't take hidden
+/// arguments or pick between signatures, so only functions without those can be used this way.
+fn func_value_type(f: &str, funcs: &Funcs) -> Result<Expr, TypeError> {
+    let cf = funcs.get(f).ok_or_else(|| TypeError::NoSuchFunc(f.to_owned()))?;
+    if cf.sigs.len() != 1 || !cf.sigs[0].hidden.is_empty() {
+        return Err(TypeError::FuncNotAValue(f.to_owned()));
+    }
+    let sig = &cf.sigs[0];
+    Ok(sig.args.iter().rev().fold(sig.ret.clone(), |t, (x, a)| {
+        // Parameters that nothing depends on are left unnamed, so they print as `a -> b`
+        let x = if t.mentions(x) { x.clone() } else { "_".to_owned() };
+        ExprKind::Pi(x, Box::new(a.clone()), Box::new(t)).into()
+    }))
+}
+
+/// A local that hides another variable needs a new name, since the types in the environment could
+/// still be referring to the one it hides. Returns the name to use and the body renamed to match.
+fn unshadow(x: &str, body: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<(String, Expr), TypeError> {
+    if !env.contains_key(x) {
+        return Ok((x.to_owned(), body.clone()));
+    }
+    let fresh = fresh_name(x, env);
+    let mut var_mapping = local_vars(env);
+    var_mapping.insert(x.to_owned(), Expr::var(&fresh));
+    Ok((fresh, body.map_vars(&var_mapping, funcs)?))
+}
+
+/// A name like `x` that isn't already a local variable
+fn fresh_name(x: &str, env: &HashMap<String, Expr>) -> String {
+    let mut fresh = format!("{}'", x);
+    while env.contains_key(&fresh) {
+        fresh.push('\'');
+    }
+    fresh
+}
+
+/// Maps each local variable to itself, for when `map_vars` should only replace some of them
+fn local_vars(env: &HashMap<String, Expr>) -> HashMap<String, Expr> {
+    env.keys().map(|k| (k.clone(), Expr::var(k))).collect()
+}
+
+/// An applicable signature instantiated for particular arguments
+struct Instantiated<'a> {
+    sig: &'a Sig,
+    arg_types: Vec<Expr>,
+    ret: Expr,
+}
+
+fn check_call(f: &str, hs: &[(String, Expr)], xs: &[Expr], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Expr, TypeError> {
+    let cf = funcs.get(f).ok_or_else(|| TypeError::NoSuchFunc(f.to_owned()))?;
+    // combine ensures that every signature has the same number of arguments
+    let n_args = cf.sigs[0].args.len();
+    if xs.len() > n_args {
+        // The extra arguments go to the function it returns
+        let t = check_call(f, hs, &xs[..n_args], funcs, env)?;
+        return check_apply(f, &t, &[], &xs[n_args..], n_args, funcs, env);
+    }
+    if xs.len() < n_args {
+        // Given too few arguments, it's used as a value and applied to the ones it's given
+        return match func_value_type(f, funcs) {
+            Ok(t) => check_apply(f, &t, hs, xs, 0, funcs, env),
+            Err(_) => Err(TypeError::WrongNumberOfArgs(f.to_owned(), n_args, xs.len())),
+        };
+    }
+
+    let ts = xs.iter().map(|x|check_expr(x, funcs, env)).collect::<Result<Vec<_>,_>>()?;
+    let mut applicable = vec![];
+    let mut errors = vec![];
+    for sig in &cf.sigs {
+        match instantiate_sig(f, sig, hs, xs, &ts, funcs, env) {
+            Ok(inst) => applicable.push(inst),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if applicable.is_empty() {
+        // Saying which hidden argument is missing is more useful than saying nothing fits
+        let only_uninferred = errors.iter().all(|e| matches!(e, TypeError::CannotInferHiddenArg(..)));
+        return Err(if cf.sigs.len() == 1 || only_uninferred {
+            errors.remove(0)
+        } else {
+            TypeError::NoMatchingSignature(f.to_owned(), ts)
+        });
+    }
+
+    // Pick the most specific signature: one whose argument types all coerce to those of every other
+    // applicable signature. If several are equally specific then the first one wins.
+    let most_specific = applicable.iter().find(|a| applicable.iter().all(|b|
+        a.arg_types.iter().zip(&b.arg_types).all(|(ta,tb)| can_coerce_type(ta, tb, funcs, env))
+    ));
+    match most_specific {
+        Some(inst) => Ok(inst.ret.clone()),
+        None => Err(TypeError::AmbiguousCall(f.to_owned(), ts, applicable.iter().map(|a|a.sig.clone()).collect())),
+    }
+}
+
+fn instantiate_sig<'a>(f: &str, sig: &'a Sig, hs: &[(String, Expr)], xs: &[Expr], ts: &[Expr], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<Instantiated<'a>, TypeError> {
+    // Explicitly given hidden arguments must be in the same order as in the signature
+    let mut positions = vec![];
+    for (h, _) in hs {
+        match sig.hidden.iter().position(|p|&p.0 == h) {
+            None => return Err(TypeError::NoSuchHiddenArg(f.to_owned(), h.clone())),
+            Some(i) => {
+                if positions.last().is_some_and(|&j| j >= i) {
+                    return Err(TypeError::HiddenArgOutOfOrder(f.to_owned(), h.clone()));
                 }
-            } else {
-                Err(TypeError::NoSuchFunc(f.clone()))
+                positions.push(i);
             }
         }
-        Expr::Array(xs) => {
-            let ts = xs.iter().map(|x|check_expr(x, funcs, env)).collect::<Result<Vec<_>,_>>()?;
-            Ok(Expr::Call("tuple".to_owned(), vec![Expr::Array(ts)]))
+    }
+
+    let hidden_values = infer_hidden_args(f, sig, hs, xs, ts, funcs, env)?;
+
+    let mut var_mapping = HashMap::new();
+    for (h, t) in &sig.hidden {
+        let x = &hidden_values[h];
+        let t0 = check_expr(x, funcs, env)?;
+        let t1 = t.map_vars(&var_mapping, funcs)?;
+        if !can_coerce_type(&t0, &t1, funcs, env) {
+            return Err(TypeError::CannotCoerceHiddenArgType(f.to_owned(), h.clone(), Box::new(x.clone()), Box::new(t0), Box::new(t1)));
+        }
+        var_mapping.insert(h.clone(), x.clone());
+    }
+
+    let mut arg_types = vec![];
+    for i in 0..ts.len() {
+        let t1 = sig.args[i].1.map_vars(&var_mapping, funcs)?;
+        if !can_coerce_value(&xs[i], &ts[i], &t1, funcs, env) {
+            return Err(TypeError::CannotCoerceArgumentType(f.to_owned(), i, Box::new(xs[i].clone()), Box::new(ts[i].clone()), Box::new(t1)));
+        }
+        if var_mapping.contains_key(&sig.args[i].0) {
+            return Err(TypeError::DuplicateArgName(sig.args[i].0.clone()));
+        }
+        var_mapping.insert(sig.args[i].0.clone(), xs[i].clone());
+        arg_types.push(t1);
+    }
+    Ok(Instantiated {
+        sig,
+        arg_types,
+        ret: sig.ret.map_vars(&var_mapping, funcs)?,
+    })
+}
+
+/// Works out values for any hidden arguments that weren't given explicitly, by matching the
+/// parameter types against the actual argument types.
+fn infer_hidden_args(f: &str, sig: &Sig, hs: &[(String, Expr)], xs: &[Expr], ts: &[Expr], funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<HashMap<String, Expr>, TypeError> {
+    let mut values: HashMap<String, Expr> = hs.iter().cloned().collect();
+    let unknown: Vec<String> = sig.hidden.iter().map(|h|h.0.clone()).filter(|h|!values.contains_key(h)).collect();
+    if unknown.is_empty() {
+        return Ok(values);
+    }
+
+    // Unknowns stay as variables so that the unifier can spot them
+    let mut var_mapping = values.clone();
+    for h in &unknown {
+        var_mapping.insert(h.clone(), Expr::var(h));
+    }
+    for (arg, x) in sig.args.iter().zip(xs) {
+        var_mapping.insert(arg.0.clone(), x.clone());
+    }
+
+    let mut candidates = HashMap::new();
+    // Type aliases have to be unfolded to see the structure inside them
+    for (arg, t) in sig.args.iter().zip(ts) {
+        unify(&arg.1.map_vars(&var_mapping, funcs)?, &funcs.unfold(t, env), &unknown, &mut candidates);
+    }
+
+    for h in unknown {
+        let cs = candidates.remove(&h).unwrap_or_default();
+        // Prefer the candidate that all the others coerce to
+        let best = cs.iter().find(|c| cs.iter().all(|d| can_coerce_type(d, c, funcs, env)));
+        match best {
+            Some(c) => {
+                values.insert(h, c.clone());
+            }
+            None if cs.is_empty() => return Err(TypeError::CannotInferHiddenArg(f.to_owned(), h)),
+            None => return Err(TypeError::ConflictingHiddenArg(f.to_owned(), h, cs)),
         }
     }
+    Ok(values)
+}
+
+/// Matches a parameter type against the type of the corresponding argument, recording a candidate
+/// value each time one of the `unknown` variables lines up with part of the argument type.
+///
+/// This doesn't decide whether the argument actually fits; that's checked afterwards once all the
+/// hidden arguments have been filled in.
+fn unify(param: &Expr, actual: &Expr, unknown: &[String], candidates: &mut HashMap<String, Vec<Expr>>) {
+    if let ExprKind::Var(h) = &param.kind {
+        if unknown.contains(h) {
+            let cs = candidates.entry(h.clone()).or_default();
+            if !cs.contains(actual) {
+                cs.push(actual.clone());
+            }
+            return;
+        }
+    }
+
+    // Element types of an actual tuple type, if written out explicitly
+    let tuple_elems = actual.as_call("tuple", 1).and_then(|ts|ts[0].is_explicit_array());
+
+    if let Some(p) = param.as_call("list", 1) {
+        if let Some(a) = actual.as_call("list", 1).or_else(|| actual.as_call("vector", 2)) {
+            unify(&p[0], &a[0], unknown, candidates);
+        } else if let Some(elems) = tuple_elems {
+            unify_elems(&p[0], elems, unknown, candidates);
+        }
+    } else if let Some(p) = param.as_call("vector", 2) {
+        if let Some(a) = actual.as_call("vector", 2) {
+            unify(&p[0], &a[0], unknown, candidates);
+            unify(&p[1], &a[1], unknown, candidates);
+        } else if let Some(elems) = tuple_elems {
+            unify_elems(&p[0], elems, unknown, candidates);
+            unify(&p[1], &ExprKind::Int(elems.len().into()).into(), unknown, candidates);
+        }
+    } else {
+        match (&param.kind, &actual.kind) {
+            (ExprKind::Call(f, _, ps), ExprKind::Call(g, _, as_)) if f == g && ps.len() == as_.len() => {
+                for (p, a) in ps.iter().zip(as_) {
+                    unify(p, a, unknown, candidates);
+                }
+            }
+            (ExprKind::Array(ps), ExprKind::Array(as_)) if ps.len() == as_.len() => {
+                for (p, a) in ps.iter().zip(as_) {
+                    unify(p, a, unknown, candidates);
+                }
+            }
+            (ExprKind::Pi(_, pa, pb), ExprKind::Pi(_, aa, ab)) => {
+                unify(pa, aa, unknown, candidates);
+                unify(pb, ab, unknown, candidates);
+            }
+            // Fields and tags line up by name
+            (ExprKind::RecordType(ps), ExprKind::RecordType(as_)) | (ExprKind::SumType(ps), ExprKind::SumType(as_)) => {
+                for (x, p) in ps {
+                    if let Some((_, a)) = as_.iter().find(|a| &a.0 == x) {
+                        unify(p, a, unknown, candidates);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Matches an element type against each of the types of a tuple. An empty tuple has no elements
+/// to go by, so its element type is taken to be `false`, which coerces to anything the other
+/// arguments say it should be.
+fn unify_elems(param: &Expr, elems: &[Expr], unknown: &[String], candidates: &mut HashMap<String, Vec<Expr>>) {
+    if elems.is_empty() {
+        unify(param, &Expr::var("false"), unknown, candidates);
+    }
+    for a in elems {
+        unify(param, a, unknown, candidates);
+    }
+}
+
+fn check_operand_is_bool(expr: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Result<(), TypeError> {
+    let t = check_expr(expr, funcs, env)?;
+    if can_coerce_type(&t, &Expr::var("bool"), funcs, env) {
+        Ok(())
+    } else {
+        Err(TypeError::ExpectedOperandToBeBool(Box::new(expr.clone()), Box::new(t)))
+    }
 }
 
 impl Expr {
-    fn map_vars(&self, var_mapping: &HashMap<String, Expr>, funcs: &HashMap<String, CheckedFunc>) -> Result<Expr,TypeError> {
-        match self {
-            Expr::Int(_) => Ok(self.clone()),
-            Expr::Var(x) => {
-                if let Some(y) = var_mapping.get(x) {
+    /// Substitutes variables, keeping the spans of everything that isn't substituted
+    fn map_vars(&self, var_mapping: &HashMap<String, Expr>, funcs: &Funcs) -> Result<Expr,TypeError> {
+        let kind = match &self.kind {
+            ExprKind::Int(_) | ExprKind::Str(_) => return Ok(self.clone()),
+            ExprKind::Var(x) => {
+                return if let Some(y) = var_mapping.get(x) {
                     Ok(y.clone())
                 } else if funcs.contains_key(x) {
                     Ok(self.clone())
                 } else {
-                    Err(TypeError::NoSuchVar(x.clone()))
+                    Err(TypeError::NoSuchVar(x.clone()).at(self.span))
+                };
+            }
+            ExprKind::Call(f, hs, xs) => ExprKind::Call(
+                f.clone(),
+                hs.iter().map(|(h,x)|Ok((h.clone(), x.map_vars(var_mapping, funcs)?))).collect::<Result<_,_>>()?,
+                xs.iter().map(|x|x.map_vars(var_mapping, funcs)).collect::<Result<_,_>>()?,
+            ),
+            ExprKind::Apply(g, xs) => ExprKind::Apply(
+                Box::new(g.map_vars(var_mapping, funcs)?),
+                xs.iter().map(|x|x.map_vars(var_mapping, funcs)).collect::<Result<_,_>>()?,
+            ),
+            ExprKind::Array(xs) => ExprKind::Array(xs.iter().map(|x|x.map_vars(var_mapping, funcs)).collect::<Result<_,_>>()?),
+            ExprKind::If(c, a, b) => ExprKind::If(
+                Box::new(c.map_vars(var_mapping, funcs)?),
+                Box::new(a.map_vars(var_mapping, funcs)?),
+                Box::new(b.map_vars(var_mapping, funcs)?),
+            ),
+            ExprKind::Let(x, t, v, b) => {
+                // Inside the body the name refers to the local variable
+                let mut inner = var_mapping.clone();
+                inner.insert(x.clone(), Expr::var(x));
+                ExprKind::Let(
+                    x.clone(),
+                    t.as_ref().map(|t|t.map_vars(var_mapping, funcs).map(Box::new)).transpose()?,
+                    Box::new(v.map_vars(var_mapping, funcs)?),
+                    Box::new(b.map_vars(&inner, funcs)?),
+                )
+            }
+            ExprKind::Lambda(x, t, b) | ExprKind::Pi(x, t, b) => {
+                let mut inner = var_mapping.clone();
+                inner.insert(x.clone(), Expr::var(x));
+                let (t, b) = (Box::new(t.map_vars(var_mapping, funcs)?), Box::new(b.map_vars(&inner, funcs)?));
+                match &self.kind {
+                    ExprKind::Lambda(..) => ExprKind::Lambda(x.clone(), t, b),
+                    _ => ExprKind::Pi(x.clone(), t, b),
                 }
             }
-            Expr::Call(f, xs) => Ok(Expr::Call(f.clone(), xs.iter().map(|x|x.map_vars(var_mapping, funcs)).collect::<Result<_,_>>()?)),
-            Expr::Array(xs) => Ok(Expr::Array(xs.iter().map(|x|x.map_vars(var_mapping, funcs)).collect::<Result<_,_>>()?)),
+            ExprKind::And(a, b) => ExprKind::And(Box::new(a.map_vars(var_mapping, funcs)?), Box::new(b.map_vars(var_mapping, funcs)?)),
+            ExprKind::Or(a, b) => ExprKind::Or(Box::new(a.map_vars(var_mapping, funcs)?), Box::new(b.map_vars(var_mapping, funcs)?)),
+            ExprKind::RecordType(fs) => {
+                // Each field's name refers to that field in the fields after it
+                let mut inner = var_mapping.clone();
+                let mut result = vec![];
+                for (x, t) in fs {
+                    result.push((x.clone(), t.map_vars(&inner, funcs)?));
+                    inner.insert(x.clone(), Expr::var(x));
+                }
+                ExprKind::RecordType(result)
+            }
+            ExprKind::SumType(fs) => ExprKind::SumType(fs.iter().map(|(a, t)| Ok((a.clone(), t.map_vars(var_mapping, funcs)?))).collect::<Result<_, _>>()?),
+            ExprKind::Record(fs) => ExprKind::Record(fs.iter().map(|(x, v)| Ok((x.clone(), v.map_vars(var_mapping, funcs)?))).collect::<Result<_, _>>()?),
+            ExprKind::Tag(a, x) => ExprKind::Tag(a.clone(), Box::new(x.map_vars(var_mapping, funcs)?)),
+            ExprKind::Field(r, x) => ExprKind::Field(Box::new(r.map_vars(var_mapping, funcs)?), x.clone()),
+            ExprKind::Match(x, arms) => ExprKind::Match(
+                Box::new(x.map_vars(var_mapping, funcs)?),
+                arms.iter().map(|(a, y, b)| {
+                    let mut inner = var_mapping.clone();
+                    inner.insert(y.clone(), Expr::var(y));
+                    Ok((a.clone(), y.clone(), b.map_vars(&inner, funcs)?))
+                }).collect::<Result<_, _>>()?,
+            ),
+        };
+        Ok(Expr { kind, span: self.span })
+    }
+
+    fn as_call(&self, name: &str, n_args: usize) -> Option<&[Expr]> {
+        match &self.kind {
+            ExprKind::Call(f, _, xs) if f == name && xs.len() == n_args => Some(xs),
+            _ => None
         }
     }
 
     fn is_label(&self, label: &str) -> bool {
-        match self {
-            Expr::Var(t) => t == label,
+        match &self.kind {
+            ExprKind::Var(t) => t == label,
             _ => false,
         }
     }
 
     fn is_list_type(&self) -> Option<&Expr> {
-        match self {
-            Expr::Call(f, xs) => if f == "list" && xs.len() == 1 {
+        match &self.kind {
+            ExprKind::Call(f, _, xs) => if f == "list" && xs.len() == 1 {
                 Some(&xs[0])
             } else {
                 None
@@ -158,8 +893,8 @@ impl Expr {
     }
 
     fn is_vector_type(&self) -> Option<(&Expr, &Expr)> {
-        match self {
-            Expr::Call(f, xs) => if f == "list" && xs.len() == 2 {
+        match &self.kind {
+            ExprKind::Call(f, _, xs) => if f == "vector" && xs.len() == 2 {
                 Some((&xs[0], &xs[1]))
             } else {
                 None
@@ -169,8 +904,8 @@ impl Expr {
     }
 
     fn is_tuple_type(&self) -> Option<&Expr> {
-        match self {
-            Expr::Call(f, xs) => if f == "tuple" && xs.len() == 1 {
+        match &self.kind {
+            ExprKind::Call(f, _, xs) => if f == "tuple" && xs.len() == 1 {
                 Some(&xs[0])
             } else {
                 None
@@ -180,21 +915,101 @@ impl Expr {
     }
 
     fn is_explicit_array(&self) -> Option<&[Expr]> {
-        match self {
-            Expr::Array(xs) => Some(xs),
+        match &self.kind {
+            ExprKind::Array(xs) => Some(xs),
             _ => None
         }
     }
 }
 
+impl Funcs<'_> {
+    fn get(&self, name: &str) -> Option<&CheckedFunc> {
+        self.checked.get(name)
+    }
+
+    fn contains_key(&self, name: &str) -> bool {
+        self.checked.contains_key(name)
+    }
+
+    fn is_user_func(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|cf| !cf.prelude)
+    }
+
+    /// Replaces closed sub-expressions that call user-defined functions with their values, so that
+    /// for example a type alias gets replaced with the type it stands for. Anything that can't be
+    /// evaluated within the step limit is left alone.
+    fn unfold(&self, expr: &Expr, env: &HashMap<String, Expr>) -> Expr {
+        if self.is_closed(expr, env) && self.mentions_user_func(expr) {
+            if let Some(v) = self.eval_closed(expr) {
+                return v.to_expr();
+            }
+        }
+        let kind = match &expr.kind {
+            ExprKind::Call(f, hs, xs) => ExprKind::Call(f.clone(), hs.clone(), xs.iter().map(|x|self.unfold(x, env)).collect()),
+            ExprKind::Array(xs) => ExprKind::Array(xs.iter().map(|x|self.unfold(x, env)).collect()),
+            ExprKind::Pi(x, a, b) => ExprKind::Pi(x.clone(), Box::new(self.unfold(a, env)), Box::new(self.unfold(b, env))),
+            ExprKind::RecordType(fs) => ExprKind::RecordType(fs.iter().map(|(x, t)| (x.clone(), self.unfold(t, env))).collect()),
+            ExprKind::SumType(fs) => ExprKind::SumType(fs.iter().map(|(a, t)| (a.clone(), self.unfold(t, env))).collect()),
+            _ => return expr.clone(),
+        };
+        Expr { kind, span: expr.span }
+    }
+
+    /// Evaluates an expression that doesn't refer to any local variables, giving up if it takes
+    /// too many steps or fails
+    fn eval_closed(&self, expr: &Expr) -> Option<Val> {
+        let mut state = self.state.borrow_mut();
+        state.fuel = Some(TYPE_EVAL_FUEL);
+        state.max_depth = Some(TYPE_EVAL_DEPTH);
+        self.program.eval_closed(expr, &mut state).ok()
+    }
+
+    /// Whether the expression refers only to global functions and not to any local variables
+    fn is_closed(&self, expr: &Expr, env: &HashMap<String, Expr>) -> bool {
+        match &expr.kind {
+            ExprKind::Int(_) | ExprKind::Str(_) => true,
+            ExprKind::Var(x) => !env.contains_key(x) && self.contains_key(x),
+            // Hidden arguments don't affect the value
+            ExprKind::Call(f, _, xs) => self.contains_key(f) && xs.iter().all(|x|self.is_closed(x, env)),
+            ExprKind::Apply(g, xs) => self.is_closed(g, env) && xs.iter().all(|x|self.is_closed(x, env)),
+            ExprKind::Array(xs) => xs.iter().all(|x|self.is_closed(x, env)),
+            ExprKind::If(c, a, b) => self.is_closed(c, env) && self.is_closed(a, env) && self.is_closed(b, env),
+            ExprKind::And(a, b) | ExprKind::Or(a, b) => self.is_closed(a, env) && self.is_closed(b, env),
+            // Not worth the bother of tracking the local variable
+            ExprKind::Let(..) | ExprKind::Lambda(..) | ExprKind::Pi(..) | ExprKind::RecordType(_) | ExprKind::Match(..) => false,
+            ExprKind::SumType(fs) | ExprKind::Record(fs) => fs.iter().all(|(_, x)| self.is_closed(x, env)),
+            ExprKind::Tag(_, x) | ExprKind::Field(x, _) => self.is_closed(x, env),
+        }
+    }
+
+    fn mentions_user_func(&self, expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::Int(_) | ExprKind::Str(_) => false,
+            ExprKind::Var(x) => self.is_user_func(x),
+            ExprKind::Call(f, _, xs) => self.is_user_func(f) || xs.iter().any(|x|self.mentions_user_func(x)),
+            ExprKind::Apply(g, xs) => self.mentions_user_func(g) || xs.iter().any(|x|self.mentions_user_func(x)),
+            ExprKind::Array(xs) => xs.iter().any(|x|self.mentions_user_func(x)),
+            ExprKind::If(c, a, b) => self.mentions_user_func(c) || self.mentions_user_func(a) || self.mentions_user_func(b),
+            ExprKind::And(a, b) | ExprKind::Or(a, b) => self.mentions_user_func(a) || self.mentions_user_func(b),
+            ExprKind::Let(_, t, v, b) => t.as_ref().is_some_and(|t| self.mentions_user_func(t)) || self.mentions_user_func(v) || self.mentions_user_func(b),
+            ExprKind::Lambda(_, a, b) | ExprKind::Pi(_, a, b) => self.mentions_user_func(a) || self.mentions_user_func(b),
+            ExprKind::RecordType(fs) | ExprKind::SumType(fs) | ExprKind::Record(fs) => fs.iter().any(|(_, x)| self.mentions_user_func(x)),
+            ExprKind::Tag(_, x) | ExprKind::Field(x, _) => self.mentions_user_func(x),
+            ExprKind::Match(x, arms) => self.mentions_user_func(x) || arms.iter().any(|(_, _, b)| self.mentions_user_func(b)),
+        }
+    }
+}
+
 /// Returns whether `sub` is known to be coercible to `sup` in the given environment.
 ///
 /// Assumes sub and sup are actually known to be types
 ///
-/// These coercions are possible:
+/// These coercions are possible, one for each entry in `COERCION_RULES`:
 ///
 /// - t < t
 /// - false < t
+/// - bool < eq
+/// - eq < type
 /// - bool < type
 /// - uint < int
 /// - list t0 < list t1           if t0 < t1
@@ -204,76 +1019,882 @@ impl Expr {
 /// - tuple ts < vector t1 n      if n==length ts and each of ts < t1
 /// - vector t0 n < tuple ts      if n==length ts and t0 < each of ts
 /// - tuple ts0 < tuple ts1       if length ts0==length ts1 and each of ts0 < corresponding ts1
+/// - pi (x:a0) -> b0 < pi (y:a1) -> b1   if a1 < a0 and b0 < b1, with x renamed to y
+/// - {x:t0, ...} < {x:t1, ...}   if every field of the second is a field of the first, and t0 < t1
+/// - {a:t0 | ...} < {a:t1 | ...} if every tag of the first is a tag of the second, and t0 < t1
 ///
-/// Note also that true = vector t 0 = tuple [] = list false, but I'm not sure how useful this is in practice
+/// Note also that true = vector t 0 = tuple [] = list false, since their only value is `[]`. They
+/// all get rewritten as `tuple []` before the rules are tried. The sum type with no tags has no
+/// values, and so gets rewritten as `false`.
 ///
-fn can_coerce_type(sub: &Expr, sup: &Expr, funcs: &HashMap<String, CheckedFunc>, env: &HashMap<String, Expr>) -> bool {
-    if sub == sup || sub.is_label("false") {
-        true
-    } else if sup.is_label("type") {
-        sub.is_label("bool")
-    } else if sup.is_label("int") {
-        sub.is_label("uint")
-    } else if let Some(t1) = sup.is_list_type() {
-        if let Some(t0) = sub.is_list_type() {
-            can_coerce_type(t0, t1, funcs, env)
-        } else if let Some((t0, _n)) = sub.is_vector_type() {
-            can_coerce_type(t0, t1, funcs, env)
-        } else if let Some(ts_expr) = sub.is_tuple_type() {
-            if let Some(ts) = ts_expr.is_explicit_array() {
-                ts.iter().all(|t|can_coerce_type(t, t1, funcs, env))
-            } else {
-                false  // more of a not sure than a false
+fn can_coerce_type(sub: &Expr, sup: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> bool {
+    let sub = &canonical_type(funcs.unfold(sub, env), funcs, env);
+    let sup = &canonical_type(funcs.unfold(sup, env), funcs, env);
+    COERCION_RULES.iter().any(|(_, rule)| rule(sub, sup, funcs, env))
+}
+
+/// Like `can_coerce_type`, but knowing the expression `x` of type `tx` as well. A record literal
+/// is compared field by field, so that a field type that depends on an earlier field sees that
+/// field's actual value, and the payload of a tag is compared in the same way.
+fn can_coerce_value(x: &Expr, tx: &Expr, t: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> bool {
+    match (&x.kind, funcs.unfold(t, env).kind) {
+        (ExprKind::Record(vs), ExprKind::RecordType(fs)) => {
+            let mut var_mapping = local_vars(env);
+            for (name, ft) in &fs {
+                let v = match vs.iter().find(|v| &v.0 == name) {
+                    Some((_, v)) => v,
+                    None => return false,
+                };
+                let fits = match (ft.map_vars(&var_mapping, funcs), check_expr(v, funcs, env)) {
+                    (Ok(ft), Ok(tv)) => can_coerce_value(v, &tv, &ft, funcs, env),
+                    _ => false,
+                };
+                if !fits {
+                    return false;
+                }
+                var_mapping.insert(name.clone(), v.clone());
             }
-        } else {
-            false
-        }
-    } else if let Some((t1, n)) = sup.is_vector_type() {
-        if let Some((t0, m)) = sub.is_vector_type() {
-            can_coerce_type(t0, t1, funcs, env) && can_prove_equal(m, n, funcs, env)
-        } else if let Some(ts_expr) = sub.is_tuple_type() {
-            if let Some(ts) = ts_expr.is_explicit_array() {
-                can_prove_equal_usize(n, ts.len(), funcs, env) && ts.iter().all(|t|can_coerce_type(t, t1, funcs, env))
-            } else {
-                false
+            true
+        }
+        (ExprKind::Tag(a, v), ExprKind::SumType(ts)) => match (ts.iter().find(|t| &t.0 == a), check_expr(v, funcs, env)) {
+            (Some((_, t)), Ok(tv)) => can_coerce_value(v, &tv, t, funcs, env),
+            _ => false,
+        },
+        _ => can_coerce_type(tx, t, funcs, env),
+    }
+}
+
+/// Says whether a rule shows that the first type coerces to the second. Any component types are
+/// compared with `can_coerce_type`.
+type CoercionRule = fn(&Expr, &Expr, &Funcs, &HashMap<String, Expr>) -> bool;
+
+/// Each rule is named as it's written in the list on `can_coerce_type`
+const COERCION_RULES: &[(&str, CoercionRule)] = &[
+    ("t < t", |sub, sup, _, _| sub == sup),
+    ("false < t", |sub, _, _, _| sub.is_label("false")),
+    ("bool < eq", |sub, sup, _, _| sub.is_label("bool") && sup.is_label("eq")),
+    ("eq < type", |sub, sup, _, _| sub.is_label("eq") && sup.is_label("type")),
+    ("bool < type", |sub, sup, _, _| sub.is_label("bool") && sup.is_label("type")),
+    ("uint < int", |sub, sup, _, _| sub.is_label("uint") && sup.is_label("int")),
+    ("list t0 < list t1", |sub, sup, funcs, env| match (sub.is_list_type(), sup.is_list_type()) {
+        (Some(t0), Some(t1)) => can_coerce_type(t0, t1, funcs, env),
+        _ => false,
+    }),
+    ("vector t0 n < list t1", |sub, sup, funcs, env| match (sub.is_vector_type(), sup.is_list_type()) {
+        (Some((t0, _)), Some(t1)) => can_coerce_type(t0, t1, funcs, env),
+        _ => false,
+    }),
+    // A tuple whose types aren't written out is more of a not sure than a false
+    ("tuple ts < list t1", |sub, sup, funcs, env| match (sub.is_tuple_type().and_then(Expr::is_explicit_array), sup.is_list_type()) {
+        (Some(ts), Some(t1)) => ts.iter().all(|t|can_coerce_type(t, t1, funcs, env)),
+        _ => false,
+    }),
+    ("vector t0 m < vector t1 n", |sub, sup, funcs, env| match (sub.is_vector_type(), sup.is_vector_type()) {
+        (Some((t0, m)), Some((t1, n))) => can_coerce_type(t0, t1, funcs, env) && can_prove_equal(m, n, funcs, env),
+        _ => false,
+    }),
+    ("tuple ts < vector t1 n", |sub, sup, funcs, env| match (sub.is_tuple_type().and_then(Expr::is_explicit_array), sup.is_vector_type()) {
+        (Some(ts), Some((t1, n))) => can_prove_equal_usize(n, ts.len(), funcs, env) && ts.iter().all(|t|can_coerce_type(t, t1, funcs, env)),
+        _ => false,
+    }),
+    ("vector t0 n < tuple ts", |sub, sup, funcs, env| match (sub.is_vector_type(), sup.is_tuple_type().and_then(Expr::is_explicit_array)) {
+        (Some((t0, n)), Some(ts)) => can_prove_equal_usize(n, ts.len(), funcs, env) && ts.iter().all(|t|can_coerce_type(t0, t, funcs, env)),
+        _ => false,
+    }),
+    ("tuple ts0 < tuple ts1", |sub, sup, funcs, env| match (sub.is_tuple_type().and_then(Expr::is_explicit_array), sup.is_tuple_type().and_then(Expr::is_explicit_array)) {
+        (Some(ts0), Some(ts1)) => ts0.len() == ts1.len() && ts0.iter().zip(ts1).all(|(t0,t1)|can_coerce_type(t0, t1, funcs, env)),
+        _ => false,
+    }),
+    // A function that accepts more and returns less can stand in for one that accepts less and returns more
+    ("pi (x:a0) -> b0 < pi (y:a1) -> b1", |sub, sup, funcs, env| match (&sub.kind, &sup.kind) {
+        (ExprKind::Pi(x, a0, b0), ExprKind::Pi(y, a1, b1)) => {
+            let mut var_mapping = local_vars(env);
+            var_mapping.insert(x.clone(), Expr::var(y));
+            let mut inner = env.clone();
+            inner.insert(y.clone(), (**a1).clone());
+            can_coerce_type(a1, a0, funcs, env) && b0.map_vars(&var_mapping, funcs).is_ok_and(|b0| can_coerce_type(&b0, b1, funcs, &inner))
+        }
+        _ => false,
+    }),
+    // Field types are compared as the types of the fields of some value of the first type, which
+    // lines up any fields that depend on earlier ones
+    ("{x:t0, ...} < {x:t1, ...}", |sub, sup, funcs, env| match (&sub.kind, &sup.kind) {
+        (ExprKind::RecordType(fs0), ExprKind::RecordType(fs1)) => {
+            let r = fresh_name("r", env);
+            let mut inner = env.clone();
+            inner.insert(r.clone(), sub.clone());
+            match (fields_of(&Expr::var(&r), fs0, funcs, &inner), fields_of(&Expr::var(&r), fs1, funcs, &inner)) {
+                (Ok(fs0), Ok(fs1)) => fs1.iter().all(|(x, t1)| fs0.iter().any(|(y, t0)| x == y && can_coerce_type(t0, t1, funcs, &inner))),
+                _ => false,
             }
-        } else {
-            false
-        }
-    } else if let Some(ts1_expr) = sup.is_tuple_type() {
-        if let Some(ts1) = ts1_expr.is_explicit_array() {
-            if let Some(ts0_expr) = sub.is_tuple_type() {
-                if let Some((t0, n)) = sub.is_vector_type() {
-                    can_prove_equal_usize(n, ts1.len(), funcs, env) && ts1.iter().all(|t1|can_coerce_type(t0, t1, funcs, env))
-                } else if let Some(ts0) = ts0_expr.is_explicit_array() {
-                    ts0.len() == ts1.len() && ts0.iter().zip(ts1).all(|(t0,t1)|can_coerce_type(t0, t1, funcs, env))
-                } else {
-                    false
+        }
+        _ => false,
+    }),
+    ("{a:t0 | ...} < {a:t1 | ...}", |sub, sup, funcs, env| match (&sub.kind, &sup.kind) {
+        (ExprKind::SumType(ts0), ExprKind::SumType(ts1)) => ts0.iter().all(|(a, t0)| ts1.iter().any(|(b, t1)| a == b && can_coerce_type(t0, t1, funcs, env))),
+        _ => false,
+    }),
+];
+
+/// Writes each of the types whose only value is `[]` as `tuple []`, and the empty sum type as `false`
+fn canonical_type(t: Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Expr {
+    if matches!(&t.kind, ExprKind::SumType(ts) if ts.is_empty()) {
+        return Expr { kind: ExprKind::Var("false".to_owned()), span: t.span };
+    }
+    let empty = t.is_label("true")
+        || t.is_tuple_type().and_then(Expr::is_explicit_array).is_some_and(|ts| ts.is_empty())
+        || t.is_vector_type().is_some_and(|(_, n)| can_prove_equal_usize(n, 0, funcs, env))
+        || t.is_list_type().is_some_and(|t0| t0.is_label("false"));
+    if empty {
+        Expr { kind: ExprKind::Call("tuple".to_owned(), vec![], vec![ExprKind::Array(vec![]).into()]), span: t.span }
+    } else {
+        t
+    }
+}
+
+/// Proves equality if they're written identically, or if they're integer expressions that
+/// normalize to the same linear combination of variables.
+fn can_prove_equal(a: &Expr, b: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> bool {
+    if a == b {
+        return true;
+    }
+    match (Linear::normalize(a, funcs, env), Linear::normalize(b, funcs, env)) {
+        (Some(x), Some(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// A constant plus a sum of variables with integer coefficients. Kept in a canonical form:
+/// the variables are sorted and none of the coefficients are zero.
+#[derive(Debug, PartialEq)]
+struct Linear {
+    constant: BigInt,
+    terms: BTreeMap<String, BigInt>,
+}
+
+impl Linear {
+    fn constant(n: BigInt) -> Self {
+        Linear {
+            constant: n,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    fn var(x: &str) -> Self {
+        let mut terms = BTreeMap::new();
+        terms.insert(x.to_owned(), BigInt::one());
+        Linear {
+            constant: BigInt::zero(),
+            terms,
+        }
+    }
+
+    /// Returns None for anything that isn't a linear integer expression
+    fn normalize(expr: &Expr, funcs: &Funcs, env: &HashMap<String, Expr>) -> Option<Self> {
+        if let Some(Val::Int(n)) = closed_value(expr, funcs) {
+            return Some(Linear::constant(n));
+        }
+        match &expr.kind {
+            ExprKind::Var(x) => Some(Linear::var(x)),
+            // Fields are as good as variables, since nothing can change them
+            ExprKind::Field(..) => Some(Linear::var(&expr.to_string())),
+            // The length of a vector is the one its type says
+            ExprKind::Call(f, _, xs) if f == "length" && xs.len() == 1 && !env.contains_key(f) => {
+                let t = funcs.unfold(&check_expr(&xs[0], funcs, env).ok()?, env);
+                let (_, n) = t.is_vector_type()?;
+                Linear::normalize(n, funcs, env)
+            }
+            ExprKind::Call(f, _, xs) => {
+                let xs = xs.iter().map(|x|Linear::normalize(x, funcs, env)).collect::<Option<Vec<_>>>()?;
+                match (f.as_str(), &xs[..]) {
+                    ("add", [a, b]) => Some(a.add(b)),
+                    ("sub", [a, b]) => Some(a.add(&b.scale(&-BigInt::one()))),
+                    ("neg", [a]) => Some(a.scale(&-BigInt::one())),
+                    ("mul", [a, b]) if a.terms.is_empty() => Some(b.scale(&a.constant)),
+                    ("mul", [a, b]) if b.terms.is_empty() => Some(a.scale(&b.constant)),
+                    _ => None,
                 }
+            }
+            _ => None,
+        }
+    }
+
+    fn add(&self, other: &Linear) -> Linear {
+        let mut terms = self.terms.clone();
+        for (x, k) in &other.terms {
+            let sum = terms.get(x).cloned().unwrap_or_default() + k;
+            if sum.is_zero() {
+                terms.remove(x);
             } else {
-                false
+                terms.insert(x.clone(), sum);
             }
-        } else {
-            false
         }
+        Linear {
+            constant: &self.constant + &other.constant,
+            terms,
+        }
+    }
+
+    fn scale(&self, k: &BigInt) -> Linear {
+        if k.is_zero() {
+            return Linear::constant(BigInt::zero());
+        }
+        Linear {
+            constant: &self.constant * k,
+            terms: self.terms.iter().map(|(x, j)|(x.clone(), j * k)).collect(),
+        }
+    }
+}
+
+/// Evaluates expressions built only from literals and prelude functions. Returns None if the
+/// expression involves anything else, or if evaluating it fails or runs out of fuel.
+fn closed_value(expr: &Expr, funcs: &Funcs) -> Option<Val> {
+    if only_prelude(expr, funcs) {
+        funcs.eval_closed(expr)
     } else {
-        false
+        None
+    }
+}
+
+fn only_prelude(expr: &Expr, funcs: &Funcs) -> bool {
+    let prelude_call = |f: &str, xs: &[Expr]| {
+        funcs.get(f).is_some_and(|cf| cf.prelude && cf.sigs[0].args.len() == xs.len())
+            && xs.iter().all(|x| only_prelude(x, funcs))
+    };
+    match &expr.kind {
+        ExprKind::Int(_) | ExprKind::Str(_) => true,
+        ExprKind::Array(xs) => xs.iter().all(|x| only_prelude(x, funcs)),
+        ExprKind::Var(f) => prelude_call(f, &[]),
+        ExprKind::Call(f, _, xs) => prelude_call(f, xs),
+        _ => false,
     }
 }
 
-/// For now, only prove equality if they're written identically
-fn can_prove_equal(a: &Expr, b: &Expr, _funcs: &HashMap<String, CheckedFunc>, _env: &HashMap<String, Expr>) -> bool {
-    a == b
+fn can_prove_equal_usize(a: &Expr, b: usize, funcs: &Funcs, env: &HashMap<String, Expr>) -> bool {
+    can_prove_equal(a, &ExprKind::Int(b.into()).into(), funcs, env)
 }
 
-fn can_prove_equal_usize(a: &Expr, b: usize, funcs: &HashMap<String, CheckedFunc>, env: &HashMap<String, Expr>) -> bool {
-    can_prove_equal(a, &Expr::Int(b.into()), funcs, env)
+fn comma_separated(xs: &[Expr]) -> String {
+    xs.iter().map(Expr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+impl TypeError {
+    /// Attaches a location, unless the error already has a more precise one
+    fn at(self, span: Option<Span>) -> Self {
+        match span {
+            Some(span) if self.span().is_none() => TypeError::At(span, Box::new(self)),
+            _ => self,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            TypeError::At(span, _) => Some(*span),
+            TypeError::WhenChecking(_, e) | TypeError::WhenCheckingSignature(_, e) => e.span(),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for TypeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Type error {:?}", self)
+        match self {
+            TypeError::ExpectedArgToBeOfTypeType(name, t, tt) => write!(f, "argument {} is declared as {}, but that has type {} rather than type", name, t, tt),
+            TypeError::DuplicateArgName(name) => write!(f, "more than one argument is called {}", name),
+            TypeError::CannotCoerceReturnType(t, ret) => write!(f, "expected the body to have type {}, but it has type {}", ret, t),
+            TypeError::CannotCoerceArgumentType(func, i, x, t, expected) => write!(f, "argument {} of {} should have type {}, but {} has type {}", i + 1, func, expected, x, t),
+            TypeError::CannotCoerceHiddenArgType(func, h, x, t, expected) => write!(f, "hidden argument {} of {} should have type {}, but {} has type {}", h, func, expected, x, t),
+            TypeError::NoSuchHiddenArg(func, h) => write!(f, "{} has no hidden argument called {}", func, h),
+            TypeError::HiddenArgOutOfOrder(func, h) => write!(f, "hidden argument {} of {} is given out of order", h, func),
+            TypeError::CannotInferHiddenArg(func, h) => write!(f, "cannot work out hidden argument {} of {}; try giving it as {{{}=...}}", h, func, h),
+            TypeError::ConflictingHiddenArg(func, h, candidates) => write!(f, "hidden argument {} of {} could be any of {}; try giving it as {{{}=...}}", h, func, comma_separated(candidates), h),
+            TypeError::HiddenArgUsedInBody(h) => write!(f, "hidden argument {} can only be used in types", h),
+            TypeError::ExpectedConditionToBeBool(c, t) => write!(f, "condition {} has type {} rather than bool", c, t),
+            TypeError::IncompatibleBranchTypes(a, b) => write!(f, "the branches have incompatible types {} and {}", a, b),
+            TypeError::ExpectedOperandToBeBool(x, t) => write!(f, "operand {} has type {} rather than bool", x, t),
+            TypeError::ExpectedLocalToBeOfTypeType(name, t, tt) => write!(f, "{} is declared as {}, but that has type {} rather than type", name, t, tt),
+            TypeError::CannotCoerceLocalType(name, t, expected) => write!(f, "{} is declared as {}, but its value has type {}", name, expected, t),
+            TypeError::ExpectedType(t, tt) => write!(f, "expected {} to be a type, but it has type {}", t, tt),
+            TypeError::NotAFunction(name, t) => write!(f, "{} has type {}, which isn't a function type", name, t),
+            TypeError::FuncNotAValue(name) => write!(f, "{} has hidden arguments or more than one signature, so it can only be called directly, not used as a value", name),
+            TypeError::DuplicateField(name) => write!(f, "{} is listed more than once", name),
+            TypeError::NotARecord(x, t) => write!(f, "expected {} to be a record, but it has type {}", x, t),
+            TypeError::NoSuchField(name, t) => write!(f, "{} has no field called {}", t, name),
+            TypeError::NotASum(x, t) => write!(f, "expected {} to be a tagged value, but it has type {}", x, t),
+            TypeError::NoSuchTag(a, t) => write!(f, "#{} isn't one of the tags of {}", a, t),
+            TypeError::DuplicateArm(a) => write!(f, "more than one arm is for #{}", a),
+            TypeError::MissingArms(tags, t) => {
+                let tags: Vec<_> = tags.iter().map(|a| format!("#{}", a)).collect();
+                write!(f, "the match has no arm for {}, which {} can also be", tags.join(", "), t)
+            }
+            TypeError::ArmTypeDependsOnValue(a, t) => write!(f, "the arm for #{} has type {}, which depends on the tag's value", a, t),
+            TypeError::NoSuchFunc(name) => write!(f, "no such function {}", name),
+            TypeError::NoSuchVar(name) => write!(f, "no such variable {}", name),
+            TypeError::WrongNumberOfArgs(func, expected, got) => write!(f, "{} takes {} arguments but was given {}", func, expected, got),
+            TypeError::NoMatchingSignature(func, ts) => write!(f, "no signature of {} accepts arguments of types {}", func, comma_separated(ts)),
+            TypeError::AmbiguousCall(func, ts, sigs) => {
+                write!(f, "call to {} with arguments of types {} could use any of these signatures:", func, comma_separated(ts))?;
+                for sig in sigs {
+                    write!(f, "\n    {} {}", func, sig)?;
+                }
+                Ok(())
+            }
+            TypeError::WhenChecking(name, e) => write!(f, "in {}: {}", name, e),
+            TypeError::WhenCheckingSignature(i, e) => write!(f, "in signature {}: {}", i + 1, e),
+            TypeError::At(_, e) => e.fmt(f),
+        }
     }
 }
 
 impl std::error::Error for TypeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{combine, parse};
+    use crate::source::Sources;
+
+    /// Checks the script along with the prelude, giving the first error without the function it's in
+    fn check(script: &str) -> Result<(), TypeError> {
+        errors(script).map_err(|es| innermost(es.into_iter().next().unwrap()))
+    }
+
+    /// Checks the script along with the prelude, giving every error
+    fn errors(script: &str) -> Result<(), Vec<TypeError>> {
+        let prelude = include_str!("prelude.jd");
+        let script = parse::parse(script, prelude.len() + 1).unwrap();
+        let program = combine::combine(&parse::parse(prelude, 0).unwrap(), &script).unwrap();
+        type_check(&program)
+    }
+
+    /// The error without the function and the expression it happened in
+    fn innermost(e: TypeError) -> TypeError {
+        match e {
+            TypeError::WhenChecking(_, e) | TypeError::At(_, e) => innermost(*e),
+            e => e,
+        }
+    }
+
+    fn expr(text: &str) -> Expr {
+        parse::parse(&format!("x : t = {};", text), 0).unwrap().decls.remove(0).1.body.unwrap()
+    }
+
+    fn proves_equal(a: &str, b: &str) -> bool {
+        can_prove_equal(&expr(a), &expr(b), &funcs(&program()), &HashMap::new())
+    }
+
+    #[test]
+    fn if_has_the_type_both_branches_coerce_to() {
+        assert!(check("x : uint = if true then 1 else 2;").is_ok());
+        assert!(check("f (n:int) : int = if true then 1 else n;").is_ok());
+        assert!(matches!(check("f (n:int) : uint = if true then 1 else n;"), Err(TypeError::CannotCoerceReturnType(..))));
+        assert!(matches!(check("x : uint = if 1 then 2 else 3;"), Err(TypeError::ExpectedConditionToBeBool(..))));
+        assert!(matches!(check("x : uint = if true then 1 else true;"), Err(TypeError::IncompatibleBranchTypes(..))));
+    }
+
+    #[test]
+    fn logical_operators_take_bools() {
+        assert!(check("x : bool = true && (false || true);").is_ok());
+        assert!(matches!(check("x : bool = 1 && true;"), Err(TypeError::ExpectedOperandToBeBool(..))));
+        assert!(matches!(check("x : bool = true || [1];"), Err(TypeError::ExpectedOperandToBeBool(..))));
+    }
+
+    #[test]
+    fn arithmetic_stays_unsigned_where_it_can() {
+        assert!(check("x : uint = 1 + 2 * 3;").is_ok());
+        assert!(check("x : uint = 7 / 2 % 3;").is_ok());
+        assert!(check("x : int = 1 - 2;").is_ok());
+        assert!(matches!(check("x : uint = 1 - 2;"), Err(TypeError::CannotCoerceReturnType(..))));
+        assert!(matches!(check("x : uint = 1 * -2;"), Err(TypeError::CannotCoerceReturnType(..))));
+        assert!(check("x : bool = 1 < 2 && -1 >= -2;").is_ok());
+    }
+
+    #[test]
+    fn recursive_functions_check_against_their_declared_signatures() {
+        assert!(check("even (n:int) : bool = if n <= 0 then true else odd (n - 1);\nodd (n:int) : bool = if n <= 0 then false else even (n - 1);").is_ok());
+        assert!(matches!(check("f (n:int) : int = if n <= 0 then true else f (n - 1);"), Err(TypeError::IncompatibleBranchTypes(..))));
+    }
+
+    #[test]
+    fn calls_use_the_most_specific_signature() {
+        let script = "double (x:uint) : uint;\ndouble (x:int) : int = x + x;\n";
+        assert!(check(&format!("{}y : uint = double 2;", script)).is_ok());
+        assert!(matches!(check(&format!("{}y : uint = double (-2);", script)), Err(TypeError::CannotCoerceReturnType(..))));
+        let script = "f (x:uint) (y:int) : int;\nf (x:int) (y:uint) : int = x + y;\n";
+        assert!(matches!(check(&format!("{}z : int = f 1 1;", script)), Err(TypeError::AmbiguousCall(..))));
+        assert!(check(&format!("{}z : int = f (-1) 1;", script)).is_ok());
+    }
+
+    #[test]
+    fn bodies_are_checked_against_every_signature() {
+        assert!(matches!(check("bad (x:uint) : uint;\nbad (x:int) : uint = x;"), Err(TypeError::WhenCheckingSignature(1, _))));
+    }
+
+    #[test]
+    fn only_eq_types_can_be_compared() {
+        assert!(check("x : bool = 1 == 2 && [1 2] == [1 (-2)];").is_ok());
+        assert!(check("x : bool = 1 == [1];").is_err());
+        assert!(matches!(check("x : bool = int == int;"), Err(TypeError::CannotCoerceHiddenArgType(..))));
+    }
+
+    #[test]
+    fn linear_expressions_are_compared_after_normalizing() {
+        assert!(proves_equal("n + 1", "1 + n"));
+        assert!(proves_equal("(m + n) + 1", "m + (n + 1)"));
+        assert!(proves_equal("2 * n - n", "n"));
+        assert!(proves_equal("n - n + 3", "1 + 2"));
+        assert!(proves_equal("-(m - n)", "n - m"));
+        assert!(proves_equal("3 * (n + 1)", "n * 3 + 3"));
+        assert!(!proves_equal("n + 1", "n"));
+        assert!(!proves_equal("m", "n"));
+        // Only linear expressions get normalized
+        assert!(!proves_equal("m * n", "n * m"));
+        assert!(check("f (n:uint) (xs:vector int (n + 1)) : vector int (1 + n) = xs;").is_ok());
+    }
+    #[test]
+    fn every_failing_function_is_reported_in_declaration_order() {
+        let script = "
+            b : uint = a;
+            a : uint = \"a\";
+            c : string = b;
+            d : uint = a + b;
+        ";
+        let messages: Vec<_> = errors(script).unwrap_err().iter().map(|e| e.to_string()).collect();
+        // `a` keeps its declared signature after failing, so `b` and `d` check against it
+        assert_eq!(messages, [
+            "in a: expected the body to have type uint, but it has type string",
+            "in c: expected the body to have type string, but it has type uint",
+        ]);
+    }
+
+    /// The prelude, plus a type alias to check that types get unfolded before the rules are tried
+    fn program() -> Program {
+        let prelude = include_str!("prelude.jd");
+        let script = "empty : type = tuple [];";
+        combine::combine(&parse::parse(prelude, 0).unwrap(), &parse::parse(script, prelude.len()).unwrap()).unwrap()
+    }
+
+    fn funcs(program: &Program) -> Funcs<'_> {
+        Funcs {
+            program,
+            checked: program.funcs.iter().map(|(name, func)| (name.clone(), CheckedFunc {
+                sigs: func.sigs.clone(),
+                prelude: func.prelude,
+            })).collect(),
+            state: RefCell::new(EvalState::new(None)),
+        }
+    }
+
+    /// `m` and `n` are unknown uints
+    fn env() -> HashMap<String, Expr> {
+        ["m", "n"].iter().map(|x| (x.to_string(), Expr::var("uint"))).collect()
+    }
+
+    fn coerces(sub: &str, sup: &str) -> bool {
+        coerces_in(&funcs(&program()), sub, sup)
+    }
+
+    fn coerces_in(funcs: &Funcs, sub: &str, sup: &str) -> bool {
+        can_coerce_type(&parse::parse_expr(sub, 0).unwrap(), &parse::parse_expr(sup, 0).unwrap(), funcs, &env())
+    }
+
+    /// Each rule with a coercion it proves. The other rules must not prove it on their own.
+    const RULE_EXAMPLES: &[(&str, &str, &str)] = &[
+        ("t < t", "string", "string"),
+        ("false < t", "false", "list string"),
+        ("bool < eq", "bool", "eq"),
+        ("eq < type", "eq", "type"),
+        ("bool < type", "bool", "type"),
+        ("uint < int", "uint", "int"),
+        ("list t0 < list t1", "list uint", "list int"),
+        ("vector t0 n < list t1", "vector uint n", "list int"),
+        ("tuple ts < list t1", "tuple [uint int]", "list int"),
+        ("vector t0 m < vector t1 n", "vector uint (n + 1)", "vector int (1 + n)"),
+        ("tuple ts < vector t1 n", "tuple [uint int]", "vector int 2"),
+        ("vector t0 n < tuple ts", "vector uint 2", "tuple [uint int]"),
+        ("tuple ts0 < tuple ts1", "tuple [uint string]", "tuple [int string]"),
+        ("pi (x:a0) -> b0 < pi (y:a1) -> b1", "int -> uint", "uint -> int"),
+        ("{x:t0, ...} < {x:t1, ...}", "{x:uint, y:int}", "{x:int}"),
+        ("{a:t0 | ...} < {a:t1 | ...}", "{| a:uint}", "{a:int | b:string}"),
+    ];
+
+    #[test]
+    fn every_rule_has_an_example() {
+        for (name, _) in COERCION_RULES {
+            assert!(RULE_EXAMPLES.iter().any(|e| e.0 == *name), "no example for {}", name);
+        }
+    }
+
+    #[test]
+    fn each_rule_proves_its_example() {
+        let program = program();
+        let funcs = funcs(&program);
+        let env = env();
+        for (name, sub, sup) in RULE_EXAMPLES {
+            let (sub, sup) = (parse::parse_expr(sub, 0).unwrap(), parse::parse_expr(sup, 0).unwrap());
+            for (rule_name, rule) in COERCION_RULES {
+                assert_eq!(rule(&sub, &sup, &funcs, &env), rule_name == name, "{} applied to {} < {}", rule_name, sub, sup);
+            }
+            assert!(can_coerce_type(&sub, &sup, &funcs, &env), "{} < {}", sub, sup);
+        }
+    }
+
+    #[test]
+    fn t_lt_t() {
+        assert!(coerces("int", "int"));
+        assert!(coerces("list (vector int 3)", "list (vector int 3)"));
+        assert!(!coerces("vector int m", "vector int n"));
+    }
+
+    #[test]
+    fn false_lt_t() {
+        assert!(coerces("false", "int"));
+        assert!(coerces("false", "type"));
+        assert!(!coerces("int", "false"));
+        assert!(!coerces("bool", "false"));
+    }
+
+    #[test]
+    fn bool_lt_eq() {
+        assert!(coerces("bool", "eq"));
+        assert!(!coerces("eq", "bool"));
+    }
+
+    #[test]
+    fn eq_lt_type() {
+        assert!(coerces("eq", "type"));
+        assert!(!coerces("type", "eq"));
+    }
+
+    #[test]
+    fn bool_lt_type() {
+        assert!(coerces("bool", "type"));
+        assert!(!coerces("type", "bool"));
+        assert!(!coerces("int", "type"));
+    }
+
+    #[test]
+    fn uint_lt_int() {
+        assert!(coerces("uint", "int"));
+        assert!(!coerces("int", "uint"));
+        assert!(!coerces("uint", "string"));
+    }
+
+    #[test]
+    fn list_lt_list() {
+        assert!(coerces("list (list uint)", "list (list int)"));
+        assert!(!coerces("list int", "list uint"));
+    }
+
+    #[test]
+    fn vector_lt_list() {
+        assert!(coerces("vector int 3", "list int"));
+        assert!(!coerces("vector int 3", "list uint"));
+        assert!(!coerces("list int", "vector int n"));
+    }
+
+    #[test]
+    fn tuple_lt_list() {
+        assert!(coerces("tuple [uint uint]", "list int"));
+        assert!(!coerces("tuple [uint string]", "list int"));
+        assert!(!coerces("list int", "tuple [int]"));
+    }
+
+    #[test]
+    fn vector_lt_vector() {
+        assert!(coerces("vector uint n", "vector int n"));
+        assert!(coerces("vector int (2 * n)", "vector int (n + n)"));
+        assert!(!coerces("vector int m", "vector int n"));
+        assert!(!coerces("vector int 2", "vector uint 2"));
+    }
+
+    #[test]
+    fn tuple_lt_vector() {
+        assert!(coerces("tuple [uint int]", "vector int 2"));
+        assert!(!coerces("tuple [uint int]", "vector int 3"));
+        assert!(!coerces("tuple [uint int]", "vector uint 2"));
+        assert!(!coerces("tuple [int]", "vector int n"));
+    }
+
+    #[test]
+    fn vector_lt_tuple() {
+        assert!(coerces("vector uint 2", "tuple [int uint]"));
+        assert!(!coerces("vector int 2", "tuple [int uint]"));
+        assert!(!coerces("vector int 2", "tuple [int]"));
+        assert!(!coerces("vector int n", "tuple [int]"));
+    }
+
+    #[test]
+    fn tuple_lt_tuple() {
+        assert!(coerces("tuple [uint (list uint)]", "tuple [int (list int)]"));
+        assert!(!coerces("tuple [int int]", "tuple [int uint]"));
+        assert!(!coerces("tuple [int]", "tuple [int int]"));
+    }
+
+    #[test]
+    fn pi_lt_pi() {
+        assert!(coerces("int -> uint", "uint -> int"));
+        assert!(coerces("pi (m:uint) -> vector int m", "pi (n:uint) -> list int"));
+        assert!(coerces("pi (m:uint) -> vector uint m", "pi (n:uint) -> vector int n"));
+        assert!(!coerces("uint -> int", "int -> int"));
+        assert!(!coerces("int -> int", "int -> uint"));
+        assert!(!coerces("pi (m:uint) -> vector int m", "pi (n:uint) -> vector int 2"));
+    }
+
+    #[test]
+    fn record_lt_record() {
+        assert!(coerces("{x:uint, y:string}", "{y:string, x:int}"));
+        assert!(coerces("{len:uint, xs:vector uint len}", "{len:int, xs:vector int len}"));
+        assert!(coerces("{len:uint, xs:vector int len}", "{xs:list int}"));
+        assert!(!coerces("{x:int}", "{x:int, y:int}"));
+        assert!(!coerces("{x:int}", "{x:uint}"));
+        // This n is the variable from outside, not the field
+        assert!(!coerces("{n:uint, xs:vector int n}", "{xs:vector int n}"));
+    }
+
+    #[test]
+    fn sum_lt_sum() {
+        assert!(coerces("{a:uint | b:string}", "{b:string | a:int | c}"));
+        assert!(!coerces("{a:int | b:string}", "{| a:int}"));
+        assert!(!coerces("{| a:int}", "{| a:uint}"));
+        assert!(!coerces("{| a:int}", "{x:int}"));
+    }
+
+    #[test]
+    fn empty_sum_is_false() {
+        assert!(coerces("{|}", "false"));
+        assert!(coerces("false", "{|}"));
+        assert!(coerces("{|}", "{x:int}"));
+        assert!(!coerces("{| a}", "{|}"));
+    }
+
+    #[test]
+    fn empty_types_are_equivalent() {
+        let empties = ["true", "tuple []", "vector int 0", "vector string (n - n)", "list false", "empty"];
+        for a in &empties {
+            for b in &empties {
+                assert!(coerces(a, b), "{} < {}", a, b);
+            }
+            assert!(coerces(a, "list int"));
+            assert!(coerces(a, "vector uint 0"));
+            assert!(!coerces(a, "vector uint 1"));
+            assert!(!coerces(a, "bool"));
+            assert!(!coerces("list int", a));
+            assert!(!coerces("vector int n", a));
+        }
+    }
+
+    #[test]
+    fn coercion_is_transitive() {
+        let types = [
+            "false", "true", "bool", "eq", "type", "uint", "int", "string",
+            "list uint", "list int", "list false", "vector uint 2", "vector int 2", "vector int n",
+            "vector int 0", "tuple []", "tuple [uint int]", "tuple [int int]", "tuple [uint string]",
+            "int -> uint", "uint -> int", "int -> int",
+            "{x:uint, y:int}", "{x:int}", "{y:int, x:uint}", "{| a:uint}", "{a:int | b}", "{|}",
+        ];
+        let program = program();
+        let funcs = funcs(&program);
+        let lt: Vec<Vec<bool>> = types.iter().map(|a| types.iter().map(|b| coerces_in(&funcs, a, b)).collect()).collect();
+        for a in 0..types.len() {
+            assert!(lt[a][a], "{} < {}", types[a], types[a]);
+            for b in 0..types.len() {
+                for c in 0..types.len() {
+                    if lt[a][b] && lt[b][c] {
+                        assert!(lt[a][c], "{} < {} < {}", types[a], types[b], types[c]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn local_definitions_shadow_what_is_outside() {
+        let script = "x : string = \"a\";\nf (x:string) : uint = y + 1 where x = 1, y = x;";
+        assert!(check(script).is_ok());
+        assert!(check(&format!("{}\ng : uint = let x = 1 in x;", script)).is_ok());
+        assert!(check(&format!("{}\ng : uint = let x : int = 1 in x;", script)).is_err());
+        assert!(check(&format!("{}\ng : bool = let x = 1 in let x = x == 1 in x;", script)).is_ok());
+        assert!(check(&format!("{}\ng : uint = (let x = 1 in x) + 1;", script)).is_ok());
+        assert!(check(&format!("{}\ng : string = x;", script)).is_ok());
+        assert!(matches!(check("g : uint = let x : uint = -1 in x;"), Err(TypeError::CannotCoerceLocalType(..))));
+    }
+
+    #[test]
+    fn lengths_of_vectors_are_the_ones_their_types_say() {
+        let script = "f {n:uint} (xs:vector int n) : vector int (length xs + 1) = push xs 0;";
+        assert!(check(script).is_ok());
+        assert!(check(&format!("{}\ng : vector int 3 = f [1 2];", script)).is_ok());
+        assert!(matches!(check(&format!("{}\ng : vector int 4 = f [1 2];", script)), Err(TypeError::CannotCoerceReturnType(..))));
+    }
+
+    #[test]
+    fn closed_types_are_evaluated() {
+        let script = "pair : type = tuple [int int];\nints (n:uint) : type = vector int n;\nfirst (p:pair) : int = index p 0;\nsecond (xs:ints (1 + 1)) : int = index xs 1;";
+        assert_eq!(type_in(script, "first [1 (-2)]").unwrap(), "int");
+        assert_eq!(type_in(script, "second [1 2]").unwrap(), "int");
+        assert!(matches!(type_in(script, "first [1]").map_err(innermost), Err(TypeError::CannotCoerceArgumentType(..))));
+        assert!(matches!(type_in(script, "second [1 2 3]").map_err(innermost), Err(TypeError::CannotCoerceArgumentType(..))));
+        assert!(coerces_in(&funcs(&program_with(script)), "ints 2", "pair"));
+    }
+
+    #[test]
+    fn huge_type_expressions_give_up_rather_than_run_out_of_memory() {
+        let script = "f (xs:vector int (length (range 100000000000))) : vector int 3 = xs;";
+        let prelude = include_str!("prelude.jd");
+        let program = combine::combine(&parse::parse(prelude, 0).unwrap(), &parse::parse(script, prelude.len() + 1).unwrap()).unwrap();
+        assert!(type_check(&program).is_err());
+        assert!(closed_value(&parse::parse_expr("range 100000000000", 0).unwrap(), &funcs(&program)).is_none());
+        assert_eq!(closed_value(&parse::parse_expr("length (range 3)", 0).unwrap(), &funcs(&program)), Some(Val::Int(3.into())));
+    }
+
+    /// The prelude and standard library along with the script
+    fn program_with(script: &str) -> Program {
+        let mut sources = Sources::new();
+        let (prelude, mut stdlib) = crate::builtins(&mut sources).unwrap();
+        if !script.is_empty() {
+            stdlib.decls.extend(parse::parse(script, sources.add("script.jd", script)).unwrap().decls);
+        }
+        combine::combine(&prelude, &stdlib).unwrap()
+    }
+
+    /// The type of the expression, printed
+    fn type_in(script: &str, text: &str) -> Result<String, TypeError> {
+        let program = program_with(script);
+        type_check(&program).unwrap();
+        type_of(&program, &parse::parse_expr(text, 0).unwrap()).map(|t| t.to_string())
+    }
+
+    /// The errors from checking the script, printed
+    fn errors_in(script: &str) -> Vec<String> {
+        type_check(&program_with(script)).unwrap_err().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn hidden_args_can_be_given_explicitly() {
+        let script = "first {t:type} {n:uint} (xs:vector t n) : t = index xs 0;";
+        assert_eq!(type_in(script, "first {t=int} [1 2]").unwrap(), "int");
+        assert_eq!(type_in(script, "first {t=int} {n=2} [1 2]").unwrap(), "int");
+        assert!(matches!(type_in(script, "first {u=int} [1]").map_err(innermost), Err(TypeError::NoSuchHiddenArg(..))));
+        assert!(matches!(type_in(script, "first {n=0} {t=int} [1]").map_err(innermost), Err(TypeError::HiddenArgOutOfOrder(..))));
+        assert!(matches!(type_in(script, "first {t=1} [1]").map_err(innermost), Err(TypeError::CannotCoerceHiddenArgType(..))));
+        assert!(matches!(type_in(script, "first {t=string} [1]").map_err(innermost), Err(TypeError::CannotCoerceArgumentType(..))));
+    }
+
+    #[test]
+    fn hidden_args_are_only_for_types() {
+        assert_eq!(errors_in("f {n:uint} (xs:vector int n) : uint = n;"), ["in f: hidden argument n can only be used in types"]);
+        assert!(type_check(&program_with("f {n:uint} (xs:vector int n) : vector int n = let ys : vector int n = xs in ys;")).is_ok());
+    }
+
+    #[test]
+    fn array_builtins_track_lengths() {
+        assert_eq!(type_in("", "push [1 2] (-3)").unwrap(), "vector int (2 + 1)");
+        assert_eq!(type_in("", "concat [1] [2 3]").unwrap(), "vector uint (1 + 2)");
+        assert_eq!(type_in("", "slice [1 2 3] 1 2").unwrap(), "vector uint (2 - 1)");
+        assert_eq!(type_in("", "\\(xs:list uint) -> slice xs 0 1").unwrap(), "pi (xs:list uint) -> list uint");
+        let script = "three (xs:vector uint 3) : uint = index xs 2;";
+        assert_eq!(type_in(script, "three (push [1 2] 3)").unwrap(), "uint");
+        assert!(matches!(type_in(script, "three (push [1] 3)").map_err(innermost), Err(TypeError::CannotCoerceArgumentType(..))));
+    }
+
+    #[test]
+    fn errors_point_at_the_innermost_expression() {
+        let span = |text| type_in("", text).unwrap_err().span();
+        assert_eq!(span("1 + (2 + \"a\")"), Some(Span { start: 4, end: 13 }));
+        assert_eq!(span("let x = 1 in x + \"a\""), Some(Span { start: 13, end: 20 }));
+        assert_eq!(span("if 1 == 1 then [1] else y"), Some(Span { start: 24, end: 25 }));
+    }
+
+    #[test]
+    fn hidden_args_are_inferred() {
+        assert_eq!(type_in("", "index [\"a\" \"b\"] 1").unwrap(), "string");
+        assert_eq!(type_in("", "concat [1] [(-1)]").unwrap(), "vector int (1 + 1)");
+        assert_eq!(type_in("", "length {t=int} [1 2]").unwrap(), "uint");
+        assert_eq!(type_in("", "reverse (range 3)").unwrap(), "vector uint 3");
+    }
+
+    #[test]
+    fn inferred_hidden_args_take_the_most_general_candidate() {
+        let script = "same {t:type} (x:t) (y:t) : t = x;";
+        assert_eq!(type_in(script, "same 1 (-1)").unwrap(), "int");
+        assert!(matches!(type_in(script, "same 1 \"a\"").map_err(innermost), Err(TypeError::ConflictingHiddenArg(..))));
+    }
+
+    #[test]
+    fn empty_arrays_have_elements_of_type_false() {
+        assert_eq!(type_in("", "length []").unwrap(), "uint");
+        assert_eq!(type_in("", "reverse []").unwrap(), "vector false 0");
+        assert_eq!(type_in("", "concat [] [1]").unwrap(), "vector uint (0 + 1)");
+    }
+
+    #[test]
+    fn uninferred_hidden_args_are_named() {
+        let script = "f {t:type} (n:uint) : uint;\nf {t:type} (n:int) : int = n;";
+        match type_in(script, "f 1").map_err(innermost) {
+            Err(TypeError::CannotInferHiddenArg(f, h)) => assert_eq!((f.as_str(), h.as_str()), ("f", "t")),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(type_in(script, "f {t=bool} 1").unwrap(), "uint");
+        assert!(matches!(type_in("", "length 1").map_err(innermost), Err(TypeError::NoMatchingSignature(..))));
+    }
+
+    #[test]
+    fn functions_can_be_used_as_values() {
+        let script = "sq (x:int) : int = x * x;\nadd3 (x:int) (y:int) (z:int) : int = x + y + z;\nvec (n:uint) : vector uint n = range n;";
+        assert_eq!(type_in(script, "map sq [1 2]").unwrap(), "vector int 2");
+        assert_eq!(type_in(script, "vec").unwrap(), "pi (n:uint) -> vector uint n");
+        assert_eq!(type_in(script, "(vec) 3").unwrap(), "vector uint 3");
+        assert_eq!(type_in(script, "add3 1").unwrap(), "int -> int -> int");
+        assert_eq!(type_in(script, "(add3 1 2) 3").unwrap(), "int");
+        assert_eq!(type_in(script, "(\\(x:int) -> x) 3").unwrap(), "int");
+        assert!(matches!(type_in(script, "map length [[1]]").map_err(innermost), Err(TypeError::FuncNotAValue(..))));
+        assert!(matches!(type_in(script, "1 2").map_err(innermost), Err(TypeError::NotAFunction(..))));
+    }
+
+    #[test]
+    fn fields_are_projected_from_records() {
+        assert_eq!(type_in("", "{x = 1, y = \"a\"}").unwrap(), "{x:uint, y:string}");
+        assert_eq!(type_in("", "{x = 1, y = \"a\"}.y").unwrap(), "string");
+        assert_eq!(type_in("", "\\(r:{len:uint, data:vector int len}) -> r.data").unwrap(), "pi (r:{len:uint, data:vector int len}) -> vector int r.len");
+        assert!(matches!(type_in("", "{x = 1}.y").map_err(innermost), Err(TypeError::NoSuchField(..))));
+        assert!(matches!(type_in("", "\\(n:int) -> n.x").map_err(innermost), Err(TypeError::NotARecord(..))));
+        assert!(matches!(type_in("", "{x = 1, x = 2}").map_err(innermost), Err(TypeError::DuplicateField(..))));
+    }
+
+    #[test]
+    fn later_fields_are_checked_against_earlier_values() {
+        let vec = "{len:uint, data:vector int len}";
+        assert!(type_check(&program_with(&format!("v : {} = {{len = 1, data = [1]}};", vec))).is_ok());
+        assert_eq!(errors_in(&format!("v : {} = {{len = 2, data = [1]}};", vec)), [
+            format!("in v: expected the body to have type {}, but it has type {{len:uint, data:tuple [uint]}}", vec),
+        ]);
+    }
+
+    #[test]
+    fn match_needs_an_arm_for_each_tag() {
+        let script = "shape : type = {circle:int | square:int | empty};";
+        let match_type = |arms| type_in(script, &format!("\\(s:shape) -> match s {{{}}}", arms)).map_err(innermost);
+        assert_eq!(match_type("#circle r -> r, #square w -> w * w, #empty -> 0").unwrap(), "pi (s:shape) -> int");
+        assert!(matches!(match_type("#circle r -> r, #empty -> 0"), Err(TypeError::MissingArms(tags, _)) if tags == ["square"]));
+        assert!(matches!(match_type("#circle r -> r, #square w -> w, #empty -> 0, #circle r -> 0"), Err(TypeError::DuplicateArm(..))));
+        assert!(matches!(match_type("#circle r -> r, #square w -> w, #empty -> 0, #star n -> n"), Err(TypeError::NoSuchTag(..))));
+        assert!(matches!(type_in(script, "match 1 {#circle r -> r}").map_err(innermost), Err(TypeError::NotASum(..))));
+    }
+
+    #[test]
+    fn arm_types_cant_depend_on_the_matched_value() {
+        let script = "count : type = {| n:uint};";
+        assert_eq!(type_in(script, "\\(c:count) -> match c {#n k -> length (range k)}").unwrap(), "pi (c:count) -> uint");
+        assert!(matches!(type_in(script, "\\(c:count) -> match c {#n k -> range k}").map_err(innermost), Err(TypeError::ArmTypeDependsOnValue(..))));
+    }
+
+    /// The standard library uses the most precise signatures the checker can prove, so it
+    /// failing to check means the checker has lost something
+    #[test]
+    fn stdlib_type_checks() {
+        let (prelude, stdlib) = crate::builtins(&mut Sources::new()).unwrap();
+        let program = combine::combine(&prelude, &stdlib).unwrap();
+        type_check(&program).unwrap();
+    }
+}